@@ -0,0 +1,156 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+
+use image::io::Reader as ImageReader;
+use image::ImageFormat;
+
+use super::imageprocessing::Failure;
+use super::log;
+
+/// Upper bounds of the dimension buckets in the histogram.
+const BUCKETS: [u32; 5] = [640, 1280, 1920, 3840, u32::MAX];
+
+/// Common aspect ratios sources are matched against, within 2%.
+const ASPECT_RATIOS: [(u32, u32); 8] = [
+    (1, 1),
+    (4, 3),
+    (3, 2),
+    (16, 9),
+    (3, 4),
+    (2, 3),
+    (9, 16),
+    (21, 9),
+];
+
+/// Bytes at the end of a source searched for its end marker. Some cameras
+/// append data after a JPEG's end of image.
+const TRAILER_SEARCH_LEN: u64 = 1024;
+
+/// Reads the dimensions of each of `files` from its header, without decoding
+/// it, and prints how widths, heights and aspect ratios are distributed.
+pub fn analyze(files: &[String]) {
+    let mut dimensions = Vec::new();
+    let mut unreadable = 0;
+    for f in files {
+        match ImageReader::open(f)
+            .and_then(|reader| reader.with_guessed_format())
+            .map(|reader| reader.into_dimensions())
+        {
+            Ok(Ok(dims)) => dimensions.push(dims),
+            _ => unreadable += 1,
+        }
+    }
+
+    println!("*************** ANALYSIS ***************");
+    println!(
+        "{} images, {} unreadable or not images",
+        dimensions.len(),
+        unreadable
+    );
+    if dimensions.is_empty() {
+        return;
+    }
+
+    print_histogram("Widths", dimensions.iter().map(|(w, _)| *w));
+    print_histogram("Heights", dimensions.iter().map(|(_, h)| *h));
+
+    let mut ratios: BTreeMap<String, usize> = BTreeMap::new();
+    for (w, h) in &dimensions {
+        *ratios.entry(aspect_label(*w, *h)).or_default() += 1;
+    }
+    println!("Aspect ratios:");
+    for (label, count) in ratios {
+        println!("  {:>7}: {}", label, count);
+    }
+}
+
+/// Reads the header of each of `files` without decoding its pixels, and
+/// returns the ones that can't be read or look truncated. Sources named with
+/// the wrong extension are only warned about, as they're decoded by their
+/// contents anyway.
+pub fn validate(files: &[String]) -> Vec<Failure> {
+    files
+        .iter()
+        .filter_map(|f| {
+            let reason = check_header(f).err()?;
+            log::error(&format!("{}: {}", f, reason));
+            Some(Failure {
+                source: f.to_owned(),
+                size: None,
+                reason,
+            })
+        })
+        .collect()
+}
+
+fn check_header(path: &str) -> Result<(), String> {
+    let reader = ImageReader::open(path)
+        .and_then(|reader| reader.with_guessed_format())
+        .map_err(|err| err.to_string())?;
+    let format = reader
+        .format()
+        .ok_or_else(|| "not a recognised image format".to_owned())?;
+    if let Ok(named) = ImageFormat::from_path(path) {
+        if named != format {
+            log::warn(&format!(
+                "{} is a {:?} image, but named as {:?}",
+                path, format, named
+            ));
+        }
+    }
+    reader.into_dimensions().map_err(|err| err.to_string())?;
+    if is_truncated(path, format).map_err(|err| err.to_string())? {
+        return Err(format!("{:?} image is truncated", format));
+    }
+    Ok(())
+}
+
+/// Whether a JPEG or PNG is missing its end marker, as interrupted downloads
+/// are. Other formats are assumed complete.
+fn is_truncated(path: &str, format: ImageFormat) -> io::Result<bool> {
+    let trailer: &[u8] = match format {
+        ImageFormat::Jpeg => &[0xFF, 0xD9],
+        ImageFormat::Png => b"IEND",
+        _ => return Ok(false),
+    };
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    file.seek(SeekFrom::Start(len.saturating_sub(TRAILER_SEARCH_LEN)))?;
+    let mut tail = Vec::new();
+    file.read_to_end(&mut tail)?;
+    Ok(!tail.windows(trailer.len()).any(|window| window == trailer))
+}
+
+fn print_histogram(name: &str, values: impl Iterator<Item = u32>) {
+    let mut counts = [0; BUCKETS.len()];
+    let (mut min, mut max) = (u32::MAX, 0);
+    for value in values {
+        let bucket = BUCKETS.iter().position(|&b| value < b).unwrap_or(0);
+        counts[bucket] += 1;
+        min = min.min(value);
+        max = max.max(value);
+    }
+
+    println!("{} (min {}, max {}):", name, min, max);
+    let mut lower = 0;
+    for (upper, count) in BUCKETS.iter().zip(counts) {
+        let range = if *upper == u32::MAX {
+            format!("{}+", lower)
+        } else {
+            format!("{}-{}", lower, upper - 1)
+        };
+        println!("  {:>9}: {}", range, count);
+        lower = *upper;
+    }
+}
+
+fn aspect_label(width: u32, height: u32) -> String {
+    let ratio = width as f64 / height.max(1) as f64;
+    ASPECT_RATIOS
+        .iter()
+        .find(|(w, h)| (ratio / (*w as f64 / *h as f64) - 1.0).abs() < 0.02)
+        .map(|(w, h)| format!("{}:{}", w, h))
+        .unwrap_or_else(|| "other".to_owned())
+}
@@ -0,0 +1,213 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use super::imageprocessing::Crop;
+
+/// Bundles every crop into a single ZIP archive at `path`, naming entries by
+/// their path relative to `root` so the directory layout is preserved.
+pub fn write_zip(path: &str, root: &str, crops: &[Crop]) -> io::Result<()> {
+    let mut zip = ZipWriter::new(BufWriter::new(File::create(path)?));
+    for crop in crops.iter().filter(|crop| crop.data_uri.is_none()) {
+        let name = Path::new(&crop.path)
+            .strip_prefix(root)
+            .unwrap_or_else(|_| Path::new(&crop.path))
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        zip.add(&name, &fs::read(&crop.path)?)?;
+    }
+    zip.finish()?.flush()
+}
+
+const LOCAL_HEADER_SIGNATURE: u32 = 0x0403_4b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x0201_4b50;
+const END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x0605_4b50;
+/// Version 2.0, the minimum for directories and stored entries.
+const ZIP_VERSION: u16 = 20;
+/// General purpose flag marking entry names as UTF-8.
+const UTF8_NAMES: u16 = 1 << 11;
+/// 1980-01-01 00:00, the earliest DOS timestamp, so archives are reproducible.
+const DOS_DATE: u16 = (1 << 5) | 1;
+const DOS_TIME: u16 = 0;
+
+struct Entry {
+    name: String,
+    name_len: u16,
+    crc: u32,
+    size: u32,
+    offset: u32,
+}
+
+/// A minimal ZIP writer. Entries are stored uncompressed: crops are already
+/// compressed images, so deflating them again gains next to nothing.
+struct ZipWriter<W: Write> {
+    inner: W,
+    offset: u64,
+    entries: Vec<Entry>,
+}
+
+impl<W: Write> ZipWriter<W> {
+    fn new(inner: W) -> ZipWriter<W> {
+        ZipWriter {
+            inner,
+            offset: 0,
+            entries: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, name: &str, data: &[u8]) -> io::Result<()> {
+        let entry = Entry {
+            name: name.to_owned(),
+            name_len: u16::try_from(name.len())
+                .map_err(|_| invalid("entry name longer than 65535 bytes"))?,
+            crc: crc32fast::hash(data),
+            size: to_u32(data.len() as u64, "entry larger than 4 GiB")?,
+            offset: to_u32(self.offset, "archive larger than 4 GiB")?,
+        };
+
+        let mut header = Vec::new();
+        header.extend(LOCAL_HEADER_SIGNATURE.to_le_bytes());
+        header.extend(ZIP_VERSION.to_le_bytes());
+        entry.write_common(&mut header);
+        header.extend(0u16.to_le_bytes()); // extra field length
+        header.extend(entry.name.as_bytes());
+
+        self.write(&header)?;
+        self.write(data)?;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<W> {
+        let start = to_u32(self.offset, "archive larger than 4 GiB")?;
+        let count =
+            u16::try_from(self.entries.len()).map_err(|_| invalid("more than 65535 entries"))?;
+
+        let mut directory = Vec::new();
+        for entry in &self.entries {
+            directory.extend(CENTRAL_HEADER_SIGNATURE.to_le_bytes());
+            directory.extend(ZIP_VERSION.to_le_bytes()); // version made by
+            directory.extend(ZIP_VERSION.to_le_bytes()); // version needed
+            entry.write_common(&mut directory);
+            directory.extend(0u16.to_le_bytes()); // extra field length
+            directory.extend(0u16.to_le_bytes()); // comment length
+            directory.extend(0u16.to_le_bytes()); // disk number
+            directory.extend(0u16.to_le_bytes()); // internal attributes
+            directory.extend(0u32.to_le_bytes()); // external attributes
+            directory.extend(entry.offset.to_le_bytes());
+            directory.extend(entry.name.as_bytes());
+        }
+        let size = to_u32(
+            directory.len() as u64,
+            "central directory larger than 4 GiB",
+        )?;
+
+        directory.extend(END_OF_CENTRAL_DIRECTORY_SIGNATURE.to_le_bytes());
+        directory.extend(0u16.to_le_bytes()); // this disk
+        directory.extend(0u16.to_le_bytes()); // disk with the central directory
+        directory.extend(count.to_le_bytes()); // entries on this disk
+        directory.extend(count.to_le_bytes()); // entries in total
+        directory.extend(size.to_le_bytes());
+        directory.extend(start.to_le_bytes());
+        directory.extend(0u16.to_le_bytes()); // comment length
+
+        self.write(&directory)?;
+        Ok(self.inner)
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.inner.write_all(data)?;
+        self.offset += data.len() as u64;
+        Ok(())
+    }
+}
+
+impl Entry {
+    /// The fields shared by the local and central directory headers, from
+    /// the general purpose flags up to the file name length.
+    fn write_common(&self, out: &mut Vec<u8>) {
+        out.extend(UTF8_NAMES.to_le_bytes());
+        out.extend(0u16.to_le_bytes()); // compression method: stored
+        out.extend(DOS_TIME.to_le_bytes());
+        out.extend(DOS_DATE.to_le_bytes());
+        out.extend(self.crc.to_le_bytes());
+        out.extend(self.size.to_le_bytes()); // compressed size
+        out.extend(self.size.to_le_bytes()); // uncompressed size
+        out.extend(self.name_len.to_le_bytes());
+    }
+}
+
+fn to_u32(n: u64, message: &str) -> io::Result<u32> {
+    u32::try_from(n).map_err(|_| invalid(message))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16_at(data: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes([data[at], data[at + 1]])
+    }
+
+    fn u32_at(data: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+    }
+
+    #[test]
+    fn archives_list_their_entries() {
+        let entries: [(&str, &[u8]); 3] = [
+            ("a_200x200.jpg", b"first"),
+            ("gallery/b_400x400.jpg", b"second entry"),
+            ("empty.jpg", b""),
+        ];
+        let mut zip = ZipWriter::new(Vec::new());
+        for (name, data) in entries {
+            zip.add(name, data).unwrap();
+        }
+        let archive = zip.finish().unwrap();
+
+        let end = archive.len() - 22;
+        assert_eq!(u32_at(&archive, end), END_OF_CENTRAL_DIRECTORY_SIGNATURE);
+        assert_eq!(u16_at(&archive, end + 10), entries.len() as u16);
+        let directory_size = u32_at(&archive, end + 12) as usize;
+        let mut at = u32_at(&archive, end + 16) as usize;
+        assert_eq!(at + directory_size, end);
+
+        for (name, data) in entries {
+            assert_eq!(u32_at(&archive, at), CENTRAL_HEADER_SIGNATURE);
+            assert_eq!(u32_at(&archive, at + 16), crc32fast::hash(data));
+            assert_eq!(u32_at(&archive, at + 20), data.len() as u32);
+            assert_eq!(u32_at(&archive, at + 24), data.len() as u32);
+            let name_len = u16_at(&archive, at + 28) as usize;
+            assert_eq!(&archive[at + 46..at + 46 + name_len], name.as_bytes());
+
+            let local = u32_at(&archive, at + 42) as usize;
+            assert_eq!(u32_at(&archive, local), LOCAL_HEADER_SIGNATURE);
+            assert_eq!(u32_at(&archive, local + 14), crc32fast::hash(data));
+            let body = local + 30 + u16_at(&archive, local + 26) as usize;
+            assert_eq!(&archive[body..body + data.len()], data);
+
+            at += 46 + name_len;
+        }
+        assert_eq!(at, end);
+    }
+
+    #[test]
+    fn overflowing_fields_are_errors() {
+        let mut zip = ZipWriter::new(Vec::new());
+        assert!(zip.add(&"a".repeat(70_000), b"").is_err());
+
+        for i in 0..=u16::MAX as usize {
+            zip.add(&i.to_string(), b"").unwrap();
+        }
+        assert!(zip.finish().is_err());
+    }
+}
@@ -0,0 +1,148 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use image::DynamicImage;
+
+use super::imageprocessing::{decode_image, TransformError, TransformOptions};
+
+type Decoded = Result<Arc<DynamicImage>, TransformError>;
+
+/// A small LRU of decoded sources, so that the crops of one source at
+/// several sizes share a single decode.
+///
+/// Entries are keyed by path only: every crop of a source is decoded with
+/// the same options, sidecar overrides included.
+pub struct DecodeCache {
+    capacity: usize,
+    entries: Mutex<VecDeque<(String, Arc<OnceLock<Decoded>>)>>,
+}
+
+impl DecodeCache {
+    /// A cache holding at most `capacity` decoded sources. With a capacity of
+    /// 0, every call decodes its source again.
+    pub fn new(capacity: usize) -> DecodeCache {
+        DecodeCache {
+            capacity,
+            entries: Mutex::new(VecDeque::with_capacity(capacity + 1)),
+        }
+    }
+
+    /// Decodes `path`, or returns the image an earlier call decoded.
+    /// Concurrent calls for the same path wait for a single decode.
+    pub fn get(&self, path: &str, options: &TransformOptions) -> Decoded {
+        if self.capacity == 0 {
+            return decode_image(path, options).map(Arc::new);
+        }
+
+        let entry = {
+            let mut entries = self.entries.lock().unwrap();
+            let entry = match entries.iter().position(|(p, _)| p == path) {
+                Some(i) => entries.remove(i).unwrap().1,
+                None => Arc::new(OnceLock::new()),
+            };
+            entries.push_back((path.to_owned(), entry.clone()));
+            if entries.len() > self.capacity {
+                entries.pop_front();
+            }
+            entry
+        };
+        entry
+            .get_or_init(|| decode_image(path, options).map(Arc::new))
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::cutter::imageprocessing::Size;
+    use crate::tests::parse;
+
+    fn fixture(name: &str) -> String {
+        format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    fn options() -> TransformOptions {
+        parse(["cutter", "-p", "/tmp/cutter-src"]).transform_options()
+    }
+
+    #[test]
+    fn sizes_of_a_source_share_a_decode() {
+        let cache = DecodeCache::new(2);
+        let options = options();
+        let small = cache
+            .get(&fixture("landscape.jpg"), &options.with_size(Size(20, 20)))
+            .unwrap();
+        let large = cache
+            .get(&fixture("landscape.jpg"), &options.with_size(Size(80, 40)))
+            .unwrap();
+        assert!(Arc::ptr_eq(&small, &large));
+    }
+
+    #[test]
+    fn least_recently_used_sources_are_evicted() {
+        let cache = DecodeCache::new(2);
+        let options = options();
+        let get = |name: &str| cache.get(&fixture(name), &options).unwrap();
+
+        let landscape = get("landscape.jpg");
+        let portrait = get("portrait.png");
+        // Using the landscape again makes the portrait the oldest entry.
+        assert!(Arc::ptr_eq(&get("landscape.jpg"), &landscape));
+        get("alpha.png");
+
+        assert!(Arc::ptr_eq(&get("landscape.jpg"), &landscape));
+        assert!(!Arc::ptr_eq(&get("portrait.png"), &portrait));
+    }
+
+    #[test]
+    fn capacity_bounds_the_cached_sources() {
+        let options = options();
+        let uncached = DecodeCache::new(0);
+        let first = uncached.get(&fixture("landscape.jpg"), &options).unwrap();
+        let second = uncached.get(&fixture("landscape.jpg"), &options).unwrap();
+        assert!(!Arc::ptr_eq(&first, &second));
+
+        let cache = DecodeCache::new(1);
+        cache.get(&fixture("landscape.jpg"), &options).unwrap();
+        cache.get(&fixture("portrait.png"), &options).unwrap();
+        assert_eq!(cache.entries.lock().unwrap().len(), 1);
+    }
+
+    /// Times decoding a large source for six crop sizes with and without the
+    /// cache. Run with `cargo test --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn cache_speeds_up_several_sizes() {
+        let path = std::env::temp_dir().join(format!("cutter-bench-{}.jpg", std::process::id()));
+        image::open(fixture("landscape.jpg"))
+            .unwrap()
+            .resize_exact(3000, 2000, image::imageops::FilterType::Triangle)
+            .save(&path)
+            .unwrap();
+        let path = path.to_string_lossy().into_owned();
+        let options = options();
+        let sizes = [200, 400, 800, 1200, 1600, 1920].map(|side| Size(side, side));
+
+        let time = |capacity: usize| {
+            let cache = DecodeCache::new(capacity);
+            let start = Instant::now();
+            for size in sizes {
+                cache.get(&path, &options.with_size(size)).unwrap();
+            }
+            start.elapsed()
+        };
+        let (uncached, cached) = (time(0), time(4));
+        let _ = std::fs::remove_file(&path);
+
+        println!(
+            "{} sizes: uncached {:?}, cached {:?}",
+            sizes.len(),
+            uncached,
+            cached
+        );
+        assert!(cached < uncached);
+    }
+}
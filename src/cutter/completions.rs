@@ -0,0 +1,225 @@
+use clap::Command;
+
+/// A shell completion scripts can be generated for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+pub fn str_to_shell(s: &str) -> Result<Shell, String> {
+    match s {
+        "bash" => Ok(Shell::Bash),
+        "zsh" => Ok(Shell::Zsh),
+        "fish" => Ok(Shell::Fish),
+        _ => Err(format!("shell must be bash, zsh or fish, not '{}'", s)),
+    }
+}
+
+/// A visible option of the command line.
+struct Flag<'a> {
+    short: Option<char>,
+    long: Option<&'a str>,
+    help: String,
+    takes_value: bool,
+    /// The values offered for the option, or file names if there are none.
+    values: Vec<&'a str>,
+}
+
+/// Completion script for `cmd` in `shell`, completing its options and
+/// subcommands, and option values from `values`, keyed by argument id, or
+/// else as file names.
+pub fn completion_script(cmd: &Command, shell: Shell, values: &[(&str, &[&str])]) -> String {
+    let name = cmd.get_name();
+    let flags: Vec<Flag> = cmd
+        .get_arguments()
+        .filter(|arg| !arg.is_hide_set() && !arg.is_positional())
+        .map(|arg| Flag {
+            short: arg.get_short(),
+            long: arg.get_long(),
+            help: arg
+                .get_help()
+                .and_then(|help| help.split(". ").next())
+                .unwrap_or_default()
+                .trim_end_matches('.')
+                .to_owned(),
+            takes_value: arg.is_takes_value_set(),
+            values: values
+                .iter()
+                .find(|(id, _)| *id == arg.get_id())
+                .map(|(_, values)| values.to_vec())
+                .unwrap_or_default(),
+        })
+        .collect();
+    let subcommands: Vec<(&str, &str)> = cmd
+        .get_subcommands()
+        .filter(|sub| !sub.is_hide_set())
+        .map(|sub| (sub.get_name(), sub.get_about().unwrap_or_default()))
+        .collect();
+
+    match shell {
+        Shell::Bash => bash(name, &flags, &subcommands),
+        Shell::Zsh => zsh(name, &flags, &subcommands),
+        Shell::Fish => fish(name, &flags, &subcommands),
+    }
+}
+
+fn bash(name: &str, flags: &[Flag], subcommands: &[(&str, &str)]) -> String {
+    let words: Vec<String> = flags
+        .iter()
+        .flat_map(|flag| {
+            let short = flag.short.map(|short| format!("-{}", short));
+            let long = flag.long.map(|long| format!("--{}", long));
+            short.into_iter().chain(long)
+        })
+        .chain(subcommands.iter().map(|(sub, _)| sub.to_string()))
+        .collect();
+    let cases: String = flags
+        .iter()
+        .filter(|flag| !flag.values.is_empty())
+        .map(|flag| {
+            let names: Vec<String> = flag
+                .short
+                .map(|short| format!("-{}", short))
+                .into_iter()
+                .chain(flag.long.map(|long| format!("--{}", long)))
+                .collect();
+            format!(
+                "        {})\n            COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))\n            return\n            ;;\n",
+                names.join("|"),
+                flag.values.join(" ")
+            )
+        })
+        .collect();
+    let function = format!("_{}", name.replace('-', "_"));
+    format!(
+        "{function}() {{\n    local cur=\"${{COMP_WORDS[COMP_CWORD]}}\"\n    local prev=\"${{COMP_WORDS[COMP_CWORD-1]}}\"\n    case \"$prev\" in\n{cases}    esac\n    COMPREPLY=($(compgen -W \"{words}\" -- \"$cur\"))\n}}\ncomplete -o default -F {function} {name}\n",
+        function = function,
+        cases = cases,
+        words = words.join(" "),
+        name = name
+    )
+}
+
+fn zsh(name: &str, flags: &[Flag], subcommands: &[(&str, &str)]) -> String {
+    let escape = |s: &str| {
+        s.replace('\'', "'\\''")
+            .replace('[', "\\[")
+            .replace(']', "\\]")
+            .replace(':', "\\:")
+    };
+    let mut script = format!("#compdef {}\n\n_arguments \\\n", name);
+    for flag in flags {
+        let value = match (flag.takes_value, flag.values.is_empty()) {
+            (true, true) => ":value:_files".to_owned(),
+            (true, false) => format!(":value:({})", flag.values.join(" ")),
+            (false, _) => String::new(),
+        };
+        let names: Vec<String> = flag
+            .short
+            .map(|short| format!("-{}", short))
+            .into_iter()
+            .chain(flag.long.map(|long| format!("--{}", long)))
+            .collect();
+        for option in &names {
+            script.push_str(&format!(
+                "    '{}[{}]{}' \\\n",
+                option,
+                escape(&flag.help),
+                value
+            ));
+        }
+    }
+    let commands: Vec<String> = subcommands
+        .iter()
+        .map(|(sub, about)| format!("{}\\:\"{}\"", sub, escape(about).replace('"', "\\\"")))
+        .collect();
+    script.push_str(&format!(
+        "    '1::command:(({}))' \\\n    '*:file:_files'\n",
+        commands.join(" ")
+    ));
+    script
+}
+
+fn fish(name: &str, flags: &[Flag], subcommands: &[(&str, &str)]) -> String {
+    let escape = |s: &str| s.replace('\\', "\\\\").replace('\'', "\\'");
+    let mut script = String::new();
+    for flag in flags {
+        let mut line = format!("complete -c {}", name);
+        if let Some(short) = flag.short {
+            line.push_str(&format!(" -s {}", short));
+        }
+        if let Some(long) = flag.long {
+            line.push_str(&format!(" -l {}", long));
+        }
+        if !flag.values.is_empty() {
+            line.push_str(&format!(" -x -a '{}'", flag.values.join(" ")));
+        } else if flag.takes_value {
+            line.push_str(" -r");
+        }
+        line.push_str(&format!(" -d '{}'\n", escape(&flag.help)));
+        script.push_str(&line);
+    }
+    for (sub, about) in subcommands {
+        script.push_str(&format!(
+            "complete -c {} -n __fish_use_subcommand -f -a {} -d '{}'\n",
+            name,
+            sub,
+            escape(about)
+        ));
+    }
+    script
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use super::*;
+    use crate::{Config, COMPLETION_VALUES};
+
+    fn script(shell: Shell) -> String {
+        completion_script(&Config::command(), shell, &COMPLETION_VALUES)
+    }
+
+    /// Checks that `script` offers some known options, written as `long`
+    /// writes them, and the on-conflict values as `on_conflict`.
+    fn assert_completes(script: &str, long: fn(&str) -> String, on_conflict: &str) {
+        for flag in ["path", "on-conflict", "s3-prefix"] {
+            assert!(script.contains(&long(flag)), "{} missing", flag);
+        }
+        assert!(script.contains("selftest"));
+        assert!(!script.contains("generate-completions"));
+        assert!(script.contains(on_conflict), "{} missing", on_conflict);
+    }
+
+    #[test]
+    fn bash_completes_flags_and_values() {
+        let script = script(Shell::Bash);
+        assert_completes(&script, |flag| format!("--{} ", flag), "--on-conflict)");
+        assert!(script.contains("compgen -W \"overwrite skip rename\""));
+    }
+
+    #[test]
+    fn zsh_completes_flags_and_values() {
+        let script = script(Shell::Zsh);
+        assert_completes(
+            &script,
+            |flag| format!("'--{}[", flag),
+            "]:value:(overwrite skip rename)'",
+        );
+        assert!(script.starts_with("#compdef cutter"));
+    }
+
+    #[test]
+    fn fish_completes_flags_and_values() {
+        let script = script(Shell::Fish);
+        assert_completes(
+            &script,
+            |flag| format!(" -l {} ", flag),
+            "-l on-conflict -x -a 'overwrite skip rename'",
+        );
+        assert!(script.contains("-a selftest"));
+    }
+}
@@ -0,0 +1,78 @@
+//! Writing pixel density into encoded JPEGs and PNGs.
+//!
+//! The `image` crate's encoders leave the density at its default (a 1:1
+//! aspect ratio for JPEG, nothing at all for PNG), which print and layout
+//! tools read as 72 or 96 DPI. Patching it after encoding lets crops meant
+//! for print carry their intended resolution.
+
+use super::icc::jpeg_segments;
+
+const MARKER_SOI: u8 = 0xD8;
+const MARKER_APP0: u8 = 0xE0;
+const JFIF_SIGNATURE: &[u8] = b"JFIF\0";
+/// JFIF density unit for dots per inch.
+const JFIF_UNITS_DPI: u8 = 1;
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+/// End of the `IHDR` chunk, which always comes first with 13 bytes of data.
+const PNG_IHDR_END: usize = PNG_SIGNATURE.len() + 4 + 4 + 13 + 4;
+/// `pHYs` unit for pixels per metre, the only real unit PNG has.
+const PNG_UNITS_METRE: u8 = 1;
+const METRES_PER_INCH: f64 = 0.0254;
+
+/// Returns `jpeg` with its `JFIF` header set to `dpi` in both directions,
+/// adding a header if there is none. Anything that isn't a JPEG is returned
+/// unchanged.
+pub fn set_jpeg_density(jpeg: &[u8], dpi: u16) -> Vec<u8> {
+    if jpeg.len() < 2 || jpeg[0] != 0xFF || jpeg[1] != MARKER_SOI {
+        return jpeg.to_vec();
+    }
+
+    let mut out = jpeg.to_vec();
+    match jpeg_segments(jpeg).next() {
+        // Signature, version, then units and the two densities.
+        Some((MARKER_APP0, payload))
+            if payload.starts_with(JFIF_SIGNATURE) && payload.len() >= 12 =>
+        {
+            let units = 2 + 4 + JFIF_SIGNATURE.len() + 2;
+            out[units] = JFIF_UNITS_DPI;
+            out[units + 1..units + 3].copy_from_slice(&dpi.to_be_bytes());
+            out[units + 3..units + 5].copy_from_slice(&dpi.to_be_bytes());
+        }
+        _ => {
+            let mut header = vec![0xFF, MARKER_APP0, 0, 16];
+            header.extend_from_slice(JFIF_SIGNATURE);
+            header.extend_from_slice(&[1, 2, JFIF_UNITS_DPI]);
+            header.extend_from_slice(&dpi.to_be_bytes());
+            header.extend_from_slice(&dpi.to_be_bytes());
+            header.extend_from_slice(&[0, 0]);
+            out.splice(2..2, header);
+        }
+    }
+    out
+}
+
+/// Returns `png` with a `pHYs` chunk for `dpi` after its `IHDR`. Anything
+/// that isn't a PNG is returned unchanged.
+pub fn set_png_density(png: &[u8], dpi: u16) -> Vec<u8> {
+    if !png.starts_with(PNG_SIGNATURE)
+        || png.len() < PNG_IHDR_END
+        || &png[PNG_SIGNATURE.len() + 4..PNG_SIGNATURE.len() + 8] != b"IHDR"
+    {
+        return png.to_vec();
+    }
+
+    let pixels_per_metre = (f64::from(dpi) / METRES_PER_INCH).round() as u32;
+    let mut body = b"pHYs".to_vec();
+    body.extend_from_slice(&pixels_per_metre.to_be_bytes());
+    body.extend_from_slice(&pixels_per_metre.to_be_bytes());
+    body.push(PNG_UNITS_METRE);
+
+    let mut chunk = ((body.len() - 4) as u32).to_be_bytes().to_vec();
+    chunk.extend_from_slice(&body);
+    chunk.extend_from_slice(&crc32fast::hash(&body).to_be_bytes());
+
+    let mut out = png.to_vec();
+    out.splice(PNG_IHDR_END..PNG_IHDR_END, chunk);
+    out
+}
@@ -0,0 +1,94 @@
+//! Reading and writing ICC colour profiles in JPEG files.
+//!
+//! The `image` crate drops embedded profiles when re-encoding, so profiles are
+//! carried over by copying the `APP2` `ICC_PROFILE` segments by hand.
+
+use std::fs;
+
+const ICC_SIGNATURE: &[u8] = b"ICC_PROFILE\0";
+const MARKER_SOI: u8 = 0xD8;
+const MARKER_SOS: u8 = 0xDA;
+const MARKER_APP0: u8 = 0xE0;
+const MARKER_APP2: u8 = 0xE2;
+/// Maximum profile bytes per segment: the 16-bit length field minus itself,
+/// the signature and the two sequence bytes.
+const MAX_CHUNK_LEN: usize = 0xFFFF - 2 - ICC_SIGNATURE.len() - 2;
+
+/// Reads the ICC profile embedded in the JPEG at `path`, if any.
+pub fn read_icc_profile(path: &str) -> Option<Vec<u8>> {
+    extract_jpeg_icc(&fs::read(path).ok()?)
+}
+
+/// Reassembles the ICC profile from the `APP2` segments of a JPEG.
+pub fn extract_jpeg_icc(data: &[u8]) -> Option<Vec<u8>> {
+    let mut chunks = Vec::new();
+    for (marker, payload) in jpeg_segments(data) {
+        if marker == MARKER_APP2 && payload.starts_with(ICC_SIGNATURE) {
+            let rest = &payload[ICC_SIGNATURE.len()..];
+            if rest.len() < 2 {
+                continue;
+            }
+            chunks.push((rest[0], &rest[2..]));
+        }
+    }
+    if chunks.is_empty() {
+        return None;
+    }
+    chunks.sort_by_key(|(seq, _)| *seq);
+    Some(
+        chunks
+            .iter()
+            .flat_map(|(_, chunk)| chunk.iter().copied())
+            .collect(),
+    )
+}
+
+/// Inserts `profile` into an encoded JPEG, after the `JFIF` header if present.
+pub fn embed_jpeg_icc(jpeg: &[u8], profile: &[u8]) -> Vec<u8> {
+    if jpeg.len() < 2 || jpeg[0] != 0xFF || jpeg[1] != MARKER_SOI {
+        return jpeg.to_vec();
+    }
+
+    let mut insert_at = 2;
+    if let Some((MARKER_APP0, payload)) = jpeg_segments(jpeg).next() {
+        insert_at += 4 + payload.len();
+    }
+
+    let chunks: Vec<&[u8]> = profile.chunks(MAX_CHUNK_LEN).collect();
+    let mut out = Vec::with_capacity(jpeg.len() + profile.len() + chunks.len() * 18);
+    out.extend_from_slice(&jpeg[..insert_at]);
+    for (i, chunk) in chunks.iter().enumerate() {
+        let len = 2 + ICC_SIGNATURE.len() + 2 + chunk.len();
+        out.extend_from_slice(&[0xFF, MARKER_APP2]);
+        out.extend_from_slice(&(len as u16).to_be_bytes());
+        out.extend_from_slice(ICC_SIGNATURE);
+        out.push(i as u8 + 1);
+        out.push(chunks.len() as u8);
+        out.extend_from_slice(chunk);
+    }
+    out.extend_from_slice(&jpeg[insert_at..]);
+    out
+}
+
+/// Iterates over the header segments of a JPEG up to the start of scan,
+/// yielding each marker with its payload.
+pub fn jpeg_segments(data: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    let mut pos = 2;
+    let valid = data.len() >= 2 && data[0] == 0xFF && data[1] == MARKER_SOI;
+    std::iter::from_fn(move || {
+        if !valid || pos + 4 > data.len() || data[pos] != 0xFF {
+            return None;
+        }
+        let marker = data[pos + 1];
+        if marker == MARKER_SOS {
+            return None;
+        }
+        let len = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        if len < 2 || pos + 2 + len > data.len() {
+            return None;
+        }
+        let payload = &data[pos + 4..pos + 2 + len];
+        pos += 2 + len;
+        Some((marker, payload))
+    })
+}
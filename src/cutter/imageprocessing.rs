@@ -1,114 +1,1981 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::{Cursor, Read};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
 use std::str;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
-use image::io::Reader as ImageReader;
+use color_quant::NeuQuant;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::{CompressionType, FilterType as PngFilterType, PngEncoder};
+use image::error::EncodingError;
+use image::imageops::FilterType;
+use image::io::{Limits, Reader as ImageReader};
+use image::{
+    DynamicImage, GenericImageView, ImageEncoder, ImageError, ImageFormat, ImageOutputFormat,
+};
 
-use super::util::{generate_thumb_path, get_file_name, print_list_iter_status};
+use super::cache::DecodeCache;
+use super::density::{set_jpeg_density, set_png_density};
+use super::icc::{embed_jpeg_icc, read_icc_profile};
+use super::log;
+use super::metadata::strip_jpeg_metadata;
+use super::progress::Progress;
+use super::sidecar::{load_sidecar, SIDECAR_SUFFIX};
+use super::signal::interrupted;
+use super::util::{
+    create_dir_with_mode, flattened_file_name, generate_thumb_path, get_file_name,
+    relative_source_dir, run_post_process, set_mode, unused_path, SizeLabel,
+};
 
-extern crate clap;
-extern crate image;
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Size(pub u32, pub u32);
+
+/// Default length of the content hash spliced into output file names.
+pub const CONTENT_HASH_LENGTH: usize = 8;
+
+/// NeuQuant samples every this many pixels; 10 is its recommended trade-off
+/// between speed and palette quality.
+const QUANTIZER_SAMPLE_FACTOR: i32 = 10;
 
+/// How long each in-flight crop gets to finish after an interrupt.
+const INTERRUPT_GRACE: Duration = Duration::from_secs(5);
+
+/// A single crop written to disk by `transform_images`.
 #[derive(Debug)]
-pub struct Size(pub u32, pub u32);
+pub struct Crop {
+    pub source: String,
+    pub requested: Size,
+    pub format: OutputFormat,
+    pub path: String,
+    /// Actual dimensions of the encoded crop, which differ from `requested`
+    /// in `fit` mode.
+    pub width: u32,
+    pub height: u32,
+    pub hash: Option<String>,
+    /// The encoded crop as a `data:` URI, set instead of writing `path` for
+    /// crops below the inline threshold.
+    pub data_uri: Option<String>,
+}
+
+/// A width:height ratio such as 16:9.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AspectRatio(pub u32, pub u32);
+
+/// How an image is resized into the requested box.
+#[derive(Debug, Clone, Copy)]
+pub enum ResizeMode {
+    /// Scale and crop to fill the box exactly.
+    Fill,
+    /// Scale to fit within the box, keeping the aspect ratio.
+    Fit,
+    /// Scale to the box, ignoring the aspect ratio.
+    Exact,
+}
+
+/// Digest used for content-hashed file names.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HashAlgorithm {
+    Md5,
+    Sha256,
+    Crc32,
+}
+
+impl HashAlgorithm {
+    /// Length of the full digest in hex digits.
+    pub fn hex_len(self) -> usize {
+        match self {
+            HashAlgorithm::Md5 => 32,
+            HashAlgorithm::Sha256 => 64,
+            HashAlgorithm::Crc32 => 8,
+        }
+    }
+}
+
+/// How crops are fingerprinted: the digest, and how many of its leading hex
+/// digits go into the file name.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ContentHash {
+    pub algorithm: HashAlgorithm,
+    pub length: usize,
+}
+
+impl Default for ContentHash {
+    fn default() -> Self {
+        ContentHash {
+            algorithm: HashAlgorithm::Md5,
+            length: CONTENT_HASH_LENGTH,
+        }
+    }
+}
+
+/// Where the crop window sits when filling a box of another aspect ratio.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Gravity {
+    /// Keep the middle of the image.
+    Center,
+    /// Keep the most detailed third of the image, or the middle if no part
+    /// stands out.
+    Auto,
+}
+
+/// Encoder settings, applied by `save_image` according to the output format.
+#[derive(Debug, Clone, Copy)]
+pub struct EncodeOptions {
+    /// Quality for lossy formats; `None` uses `default_quality` for the format.
+    pub quality: Option<u8>,
+    pub png_compression: CompressionType,
+    pub png_filter: PngFilterType,
+    /// Remove EXIF, XMP, IPTC and comments from encoded output.
+    pub strip_metadata: bool,
+    /// Reduce PNG output to a palette of this many colours.
+    pub quantize_colors: Option<u16>,
+    /// Pixel density to record in JPEG and PNG output; `None` leaves the
+    /// encoder's default.
+    pub dpi: Option<u16>,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> Self {
+        EncodeOptions {
+            quality: None,
+            png_compression: CompressionType::Default,
+            png_filter: PngFilterType::Adaptive,
+            strip_metadata: true,
+            quantize_colors: None,
+            dpi: None,
+        }
+    }
+}
+
+/// Where and how crops are written.
+#[derive(Debug, Clone)]
+pub struct OutputOptions {
+    pub path: String,
+    /// Directory sources are found in; their layout below it is kept in the output.
+    pub source_root: String,
+    /// Leading path components removed from each source's relative directory.
+    pub strip_prefix: Option<String>,
+    /// Write every crop directly into `path`, prefixing its name with the
+    /// source's relative directory instead of recreating it.
+    pub flatten: bool,
+    /// How dimensions are written in crop file names.
+    pub size_label: SizeLabel,
+    /// Process sources with identical contents once, linking the others'
+    /// crops to the same files.
+    pub dedupe: bool,
+    pub content_hash: Option<ContentHash>,
+    /// Copy every source next to its crops, for a self-contained gallery.
+    pub include_originals: bool,
+    /// Command run on every written crop, with `{}` replaced by its path.
+    pub post_process_cmd: Option<String>,
+    /// Crops that encode to fewer bytes than this are kept as data URIs
+    /// instead of being written.
+    pub inline_threshold: Option<u64>,
+    /// Exact path to write the only crop to, bypassing the generated name.
+    pub single_output: Option<String>,
+    /// Permissions set on written crops and created directories (unix only).
+    pub file_mode: Option<u32>,
+    pub dir_mode: Option<u32>,
+    /// Formats each crop is written in besides JPEG.
+    pub extra_formats: Vec<OutputFormat>,
+    /// Stop writing crops once they would take up more than this many bytes.
+    pub max_total_bytes: Option<u64>,
+    /// What to do when a crop's file already exists.
+    pub on_conflict: OnConflict,
+}
+
+/// What to do when the file a crop is written to already exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnConflict {
+    /// Replace the file.
+    Overwrite,
+    /// Keep the file, and list it as the crop.
+    Skip,
+    /// Write the crop next to it with a `-1`, `-2`, ... suffix.
+    Rename,
+}
+
+impl OutputOptions {
+    /// Formats each crop is written in: JPEG, then the extra formats.
+    fn formats(&self) -> Vec<OutputFormat> {
+        let mut formats = vec![OutputFormat::Jpeg];
+        for format in &self.extra_formats {
+            if !formats.contains(format) {
+                formats.push(*format);
+            }
+        }
+        formats
+    }
+}
+
+/// A format crops can be written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OutputFormat {
+    Jpeg,
+    Png,
+}
+
+impl OutputFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Png => "png",
+        }
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::Png => "image/png",
+        }
+    }
+}
+
+/// Clockwise rotation applied to sources before resizing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rotation {
+    None,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+}
+
+/// Mirroring applied to sources after rotating them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Flip {
+    None,
+    Horizontal,
+    Vertical,
+    Both,
+}
+
+/// Options for a single transform.
+#[derive(Debug, Clone, Copy)]
+pub struct TransformOptions {
+    pub width: u32,
+    pub height: u32,
+    pub filter: FilterType,
+    pub resize_mode: ResizeMode,
+    pub rotate: Rotation,
+    pub flip: Flip,
+    /// Copy the source's embedded ICC profile into the output.
+    pub preserve_icc: bool,
+    /// Skip sources whose longest edge is shorter than this.
+    pub min_source_dimension: Option<u32>,
+    /// Crop to the largest box of this ratio that fits the source, capping
+    /// its longest edge at the larger of `width` and `height`.
+    pub crop_aspect: Option<AspectRatio>,
+    /// Swap `width` and `height` when the source's orientation doesn't match
+    /// the requested size's, so portraits get portrait crops.
+    pub orientation_swap: bool,
+    /// Refuse to decode sources that need more memory than this, instead of
+    /// the `image` crate's default of 512 MiB.
+    pub max_image_bytes: Option<u64>,
+    /// Refuse to decode sources wider or taller than this.
+    pub max_image_dimension: Option<u32>,
+    /// Number of decoded sources kept in memory for their other crop sizes.
+    pub decode_cache_size: usize,
+    /// Crop away borders of the corner pixel's colour before resizing,
+    /// treating channels within this distance of it as the same colour.
+    pub trim: Option<u8>,
+    /// Keep sources smaller than the requested size at their own size
+    /// instead of enlarging them.
+    pub downscale_only: bool,
+    /// Where the crop window sits when filling the requested size.
+    pub gravity: Gravity,
+    /// Crops spawned at a time; the next batch starts once all of these are
+    /// done. `None` spawns every crop at once.
+    pub batch_size: Option<NonZeroUsize>,
+    /// `--scale`, applied to sidecar sizes as it was to the global ones.
+    pub scale: Option<f64>,
+}
+
+impl TransformOptions {
+    pub fn with_size(&self, size: Size) -> TransformOptions {
+        TransformOptions {
+            width: size.0,
+            height: size.1,
+            ..*self
+        }
+    }
+}
+
+/// A source that could not be turned into a crop.
+#[derive(Debug)]
+pub struct Failure {
+    pub source: String,
+    /// The crop size that failed, when the rest of the source's sizes may
+    /// still have been made.
+    pub size: Option<Size>,
+    pub reason: String,
+}
+
+/// Everything `transform_images` produced, and everything it failed to.
+#[derive(Debug, Default)]
+pub struct TransformReport {
+    pub operations: usize,
+    pub crops: Vec<Crop>,
+    pub failures: Vec<Failure>,
+    /// Set when the run was cut short by Ctrl-C.
+    pub interrupted: bool,
+    /// Requested sizes larger than some of the sources, with how many sources
+    /// had to be upscaled to produce them.
+    pub undersized: HashMap<Size, usize>,
+    /// Operations not done because the output budget ran out.
+    pub over_budget: usize,
+    /// Sources copied next to their crops, with `include_originals`.
+    pub originals: Vec<String>,
+}
+
+/// Bytes of crops written so far, against an optional limit.
+struct OutputBudget {
+    limit: Option<u64>,
+    used: AtomicU64,
+    exhausted: AtomicBool,
+}
+
+impl OutputBudget {
+    fn new(limit: Option<u64>) -> OutputBudget {
+        OutputBudget {
+            limit,
+            used: AtomicU64::new(0),
+            exhausted: AtomicBool::new(false),
+        }
+    }
+
+    /// Whether a crop has already been refused for going over the limit.
+    fn exhausted(&self) -> bool {
+        self.exhausted.load(Ordering::SeqCst)
+    }
+
+    /// Counts `bytes` against the limit, or refuses them, counting nothing,
+    /// when that would go over it.
+    fn reserve(&self, bytes: u64) -> bool {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return true,
+        };
+        let reserved = self
+            .used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                Some(used + bytes).filter(|total| *total <= limit)
+            })
+            .is_ok();
+        if !reserved {
+            self.exhausted.store(true, Ordering::SeqCst);
+        }
+        reserved
+    }
+}
+
+pub fn str_to_filter(s: &str) -> Result<FilterType, String> {
+    match s {
+        "nearest" => Ok(FilterType::Nearest),
+        "triangle" => Ok(FilterType::Triangle),
+        "catmullrom" => Ok(FilterType::CatmullRom),
+        "gaussian" => Ok(FilterType::Gaussian),
+        "lanczos3" => Ok(FilterType::Lanczos3),
+        _ => Err(format!("unknown filter '{}'", s)),
+    }
+}
+
+pub fn str_to_resize_mode(s: &str) -> Result<ResizeMode, String> {
+    match s {
+        "fill" => Ok(ResizeMode::Fill),
+        "fit" => Ok(ResizeMode::Fit),
+        "exact" => Ok(ResizeMode::Exact),
+        _ => Err(format!("unknown resize mode '{}'", s)),
+    }
+}
+
+pub fn str_to_hash_algorithm(s: &str) -> Result<HashAlgorithm, String> {
+    match s {
+        "md5" => Ok(HashAlgorithm::Md5),
+        "sha256" => Ok(HashAlgorithm::Sha256),
+        "crc32" => Ok(HashAlgorithm::Crc32),
+        // Neither has an implementation among cutter's dependencies.
+        "blake3" | "xxhash" => Err(format!("{} is not supported, use md5, sha256 or crc32", s)),
+        _ => Err(format!("unknown hash algorithm '{}'", s)),
+    }
+}
+
+pub fn str_to_gravity(s: &str) -> Result<Gravity, String> {
+    match s {
+        "center" => Ok(Gravity::Center),
+        "auto" => Ok(Gravity::Auto),
+        _ => Err(format!("unknown gravity '{}'", s)),
+    }
+}
+
+pub fn str_to_output_format(s: &str) -> Result<OutputFormat, String> {
+    match s {
+        "jpeg" | "jpg" => Ok(OutputFormat::Jpeg),
+        "png" => Ok(OutputFormat::Png),
+        // The image crate only decodes WebP and has no AVIF encoder built in.
+        "webp" | "avif" => Err(format!("{} output is not supported", s)),
+        _ => Err(format!("unknown output format '{}'", s)),
+    }
+}
+
+pub fn str_to_on_conflict(s: &str) -> Result<OnConflict, String> {
+    match s {
+        "overwrite" => Ok(OnConflict::Overwrite),
+        "skip" => Ok(OnConflict::Skip),
+        "rename" => Ok(OnConflict::Rename),
+        _ => Err(format!(
+            "on conflict must be overwrite, skip or rename, not '{}'",
+            s
+        )),
+    }
+}
+
+pub fn str_to_png_compression(s: &str) -> Result<CompressionType, String> {
+    match s {
+        "fast" => Ok(CompressionType::Fast),
+        "default" => Ok(CompressionType::Default),
+        "best" => Ok(CompressionType::Best),
+        _ => Err(format!(
+            "PNG compression must be fast, default or best, not '{}'",
+            s
+        )),
+    }
+}
+
+/// Parses a palette size for `--quantize-colors`, which PNG limits to 256.
+pub fn str_to_color_count(s: &str) -> Result<u16, String> {
+    match s.parse() {
+        Ok(colors) if (2..=256).contains(&colors) => Ok(colors),
+        _ => Err(format!("color count must be 2 to 256, not '{}'", s)),
+    }
+}
+
+pub fn str_to_dpi(s: &str) -> Result<u16, String> {
+    match s.parse() {
+        Ok(dpi) if dpi > 0 => Ok(dpi),
+        _ => Err(format!("DPI must be 1 to 65535, not '{}'", s)),
+    }
+}
+
+pub fn str_to_flip(s: &str) -> Result<Flip, String> {
+    match s {
+        "none" => Ok(Flip::None),
+        "horizontal" => Ok(Flip::Horizontal),
+        "vertical" => Ok(Flip::Vertical),
+        "both" => Ok(Flip::Both),
+        _ => Err(format!(
+            "flip must be none, horizontal, vertical or both, not '{}'",
+            s
+        )),
+    }
+}
+
+pub fn str_to_rotation(s: &str) -> Result<Rotation, String> {
+    match s {
+        "0" => Ok(Rotation::None),
+        "90" => Ok(Rotation::Rotate90),
+        "180" => Ok(Rotation::Rotate180),
+        "270" => Ok(Rotation::Rotate270),
+        _ => Err(format!("rotation must be 0, 90, 180 or 270, not '{}'", s)),
+    }
+}
+
+pub fn str_to_aspect(s: &str) -> Result<AspectRatio, String> {
+    let (width, height) = s
+        .split_once(':')
+        .ok_or_else(|| format!("aspect ratio must look like 16:9, not '{}'", s))?;
+    let parse = |n: &str| match n.parse::<u32>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("invalid aspect ratio '{}'", s)),
+    };
+    Ok(AspectRatio(parse(width)?, parse(height)?))
+}
 
 pub fn str_to_size(s: &str) -> Result<Size, String> {
-    let width: u32 = s.split('x').collect::<Vec<&str>>()[0]
-        .parse()
-        .expect("first elem should be a number");
-    let height: u32 = s.split('x').collect::<Vec<&str>>()[1]
-        .parse()
-        .expect("second elem should be a number");
-    Ok(Size(width, height))
+    let invalid = || format!("size must be WIDTHxHEIGHT in whole pixels, not '{}'", s);
+    let (width, height) = s.trim().split_once('x').ok_or_else(invalid)?;
+    let length = |length: &str| match length.trim().parse() {
+        Ok(length) if length > 0 => Ok(length),
+        _ => Err(invalid()),
+    };
+    Ok(Size(length(width)?, length(height)?))
 }
 
+/// Largest side `--scale` may scale a crop size to, the most a JPEG can hold.
+pub const MAX_SCALED_DIMENSION: u32 = 65535;
+
+/// Multiplies `size` by `factor`, rounding to whole pixels and keeping each
+/// side at least a pixel. Sides above `MAX_SCALED_DIMENSION` are an error.
+pub fn scale_size(size: Size, factor: f64) -> Result<Size, String> {
+    let scale = |length: u32| {
+        let scaled = (length as f64 * factor).round().max(1.0);
+        if scaled > MAX_SCALED_DIMENSION as f64 {
+            return Err(format!(
+                "--scale {} makes {}x{} larger than {} pixels",
+                factor, size.0, size.1, MAX_SCALED_DIMENSION
+            ));
+        }
+        Ok(scaled as u32)
+    };
+    Ok(Size(scale(size.0)?, scale(size.1)?))
+}
+
+/// Sorts `sizes` and drops repeated ones, returning the sizes that were
+/// given more than once.
+pub fn dedupe_sizes(sizes: &mut Vec<Size>) -> Vec<Size> {
+    sizes.sort_by_key(|size| (size.0, size.1));
+    let mut repeated = Vec::new();
+    sizes.dedup_by(|size, previous| {
+        let same = size == previous;
+        if same {
+            repeated.push(*size);
+        }
+        same
+    });
+    repeated.dedup();
+    repeated
+}
+
+pub fn str_to_scale(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(factor) if factor.is_finite() && factor > 0.0 => Ok(factor),
+        _ => Err(format!("scale must be a positive number, not '{}'", s)),
+    }
+}
+
+/// The crops of a source at one size, one per output format, whether the
+/// source had to be upscaled, and the first error of the post-process
+/// command, if any.
+type CropResult = Result<(Vec<Crop>, bool, Option<String>), TransformError>;
+
 pub async fn transform_images(
     files: Vec<String>,
-    output_path: String,
-    sizes: &Vec<Size>,
-    verbose: bool,
-) -> Vec<String> {
+    sizes: &[Size],
+    options: &TransformOptions,
+    encode: &EncodeOptions,
+    output: &OutputOptions,
+    completed: Vec<Crop>,
+    progress: &Progress,
+) -> TransformReport {
+    let (files, non_images): (Vec<String>, Vec<String>) =
+        files.into_iter().partition(|f| is_image(f));
+    if !non_images.is_empty() {
+        log::info(&format!("Skipped {} non-image files", non_images.len()));
+    }
+
+    // Failed downloads and truncated copies leave empty placeholders, which
+    // would otherwise only show up as a vague decode error.
+    let (empty, files): (Vec<String>, Vec<String>) = files
+        .into_iter()
+        .partition(|f| fs::metadata(f).is_ok_and(|meta| meta.len() == 0));
+    for f in &empty {
+        log::error(&format!("{}: empty file", f));
+    }
+
+    let files = match options.min_source_dimension {
+        Some(min) => {
+            let (files, tiny): (Vec<String>, Vec<String>) =
+                files.into_iter().partition(|f| !is_too_small(f, min));
+            for f in &tiny {
+                log::info(&format!("Skipping {}: smaller than {}px", f, min));
+            }
+            files
+        }
+        None => files,
+    };
+
+    let (files, duplicates) = if output.dedupe {
+        partition_duplicates(files)
+    } else {
+        (files, Vec::new())
+    };
+    if !duplicates.is_empty() {
+        log::info(&format!(
+            "Found {} duplicate sources, linking them to the original's crops",
+            duplicates.len()
+        ));
+    }
+
+    let locations = Arc::new(crop_locations(
+        files
+            .iter()
+            .chain(duplicates.iter().map(|(duplicate, _)| duplicate)),
+        output,
+    ));
+
     let numfiles = files.len();
-    let operations = numfiles * sizes.len();
-    println!("Processing {} files, {} operations", numfiles, operations);
+    let jobs: Vec<(String, Vec<Size>, TransformOptions)> = files
+        .into_iter()
+        .map(|f| {
+            let overrides = load_sidecar(&f).and_then(|sidecar| {
+                sidecar
+                    .map(|sidecar| {
+                        sidecar
+                            .crop_sizes(sizes, options.scale)
+                            .map(|file_sizes| (file_sizes, sidecar.options(options)))
+                            .map_err(|err| format!("{}{}: {}", f, SIDECAR_SUFFIX, err))
+                    })
+                    .transpose()
+            });
+            let (file_sizes, file_options) = match overrides {
+                Ok(Some(overrides)) => overrides,
+                Ok(None) => (sizes.to_vec(), *options),
+                Err(err) => {
+                    log::warn(&format!("ignoring sidecar: {}", err));
+                    (sizes.to_vec(), *options)
+                }
+            };
+            (f, file_sizes, file_options)
+        })
+        .collect();
 
-    let mut tasks = Vec::new();
-    for f in files {
-        for size in sizes {
-            let width = size.0;
-            let height = size.1;
-
-            let ff = f.to_owned();
-            let op = output_path.to_owned();
-
-            let task: tokio::task::JoinHandle<Result<String, _>> = tokio::spawn(async move {
-                let thumb_path = format!(
-                    "{}/{}",
-                    op,
-                    generate_thumb_path(&get_file_name(&ff.to_owned()), width, height, "jpg")
-                );
-                let image = match transform_image(&ff, width, height) {
+    // Crops finished by an earlier, interrupted run. A size is only skipped
+    // once it exists in every format; otherwise the missing ones are made.
+    let formats = output.formats();
+    let done: HashSet<(String, Size, OutputFormat)> = completed
+        .iter()
+        .map(|crop| (crop.source.to_owned(), crop.requested, crop.format))
+        .collect();
+    let jobs: Vec<_> = jobs
+        .into_iter()
+        .map(|(f, file_sizes, file_options)| {
+            let file_sizes: Vec<(Size, Vec<OutputFormat>)> = file_sizes
+                .into_iter()
+                .map(|size| {
+                    let missing: Vec<OutputFormat> = formats
+                        .iter()
+                        .copied()
+                        .filter(|format| !done.contains(&(f.to_owned(), size, *format)))
+                        .collect();
+                    (size, missing)
+                })
+                .filter(|(_, missing)| !missing.is_empty())
+                .collect();
+            (f, file_sizes, file_options)
+        })
+        .collect();
+    if !completed.is_empty() {
+        log::info(&format!("Resuming: {} crops already done", completed.len()));
+    }
+    let operations: usize = jobs.iter().map(|(_, file_sizes, _)| file_sizes.len()).sum();
+    log::info(&format!(
+        "Processing {} files, {} operations",
+        numfiles, operations
+    ));
+
+    let cache = Arc::new(DecodeCache::new(options.decode_cache_size));
+    let budget = Arc::new(OutputBudget::new(output.max_total_bytes));
+    let spawn_crop =
+        |ff: String, size: Size, formats: Vec<OutputFormat>, file_options: TransformOptions| {
+            let options = file_options.with_size(size);
+            let cache = cache.clone();
+            let budget = budget.clone();
+            let locations = locations.clone();
+            let output = output.to_owned();
+            let encode = *encode;
+
+            tokio::spawn(async move {
+                if interrupted() {
+                    return Err(TransformError::Interrupted);
+                }
+                if budget.exhausted() {
+                    return Err(TransformError::OverBudget);
+                }
+
+                let source_image = match cache.get(&ff, &options) {
                     Ok(i) => i,
                     Err(err) => {
-                        println!("transform error: {:?}", err);
-                        return Err("a");
+                        log::error(&format!("transform error: {}", err));
+                        return Err(err);
                     }
                 };
+                let upscaled = needs_upscale(source_image.dimensions(), &options);
+                let image = resize_image(&source_image, &options);
 
-                save_image(&image, &thumb_path);
-                Ok(thumb_path)
-            });
+                let icc_profile = if options.preserve_icc {
+                    read_icc_profile(&ff)
+                } else {
+                    None
+                };
+
+                let (output_dir, file_name) = locations[&ff].to_owned();
+                create_dir_with_mode(&output_dir, &output.path, output.dir_mode)
+                    .map_err(|err| TransformError::IoError(err.to_string()))?;
+
+                // Every format is encoded before any is written, so a crop that
+                // would go over the budget leaves no files behind.
+                let mut encoded = Vec::new();
+                for format in formats {
+                    let extension = format.extension();
+                    let (data, hash) = encode_crop(
+                        &image,
+                        extension,
+                        &encode,
+                        icc_profile.as_deref(),
+                        output.content_hash,
+                    )?;
+                    let mut path = match &output.single_output {
+                        Some(path) => path.to_owned(),
+                        None => crop_path(
+                            &output_dir.to_string_lossy(),
+                            &file_name,
+                            &image,
+                            hash.as_deref(),
+                            extension,
+                            output.size_label,
+                        ),
+                    };
+
+                    let data_uri = match output.inline_threshold {
+                        Some(threshold) if (data.len() as u64) < threshold => Some(format!(
+                            "data:{};base64,{}",
+                            format.mime_type(),
+                            base64::encode(&data)
+                        )),
+                        _ => None,
+                    };
+
+                    let exists = Path::new(&path).exists();
+                    let skipped =
+                        data_uri.is_none() && exists && output.on_conflict == OnConflict::Skip;
+                    let write = data_uri.is_none() && !skipped;
+                    if write && exists && output.on_conflict == OnConflict::Rename {
+                        path = unused_path(&path);
+                    }
+                    // A skipped crop keeps the file already there, so record that
+                    // rather than the image that wasn't written.
+                    let (width, height, hash) = if skipped {
+                        existing_crop(&path, output.content_hash)?
+                    } else {
+                        (image.width(), image.height(), hash)
+                    };
+
+                    let crop = Crop {
+                        source: ff.to_owned(),
+                        requested: size,
+                        format,
+                        path,
+                        width,
+                        height,
+                        hash,
+                        data_uri,
+                    };
+                    encoded.push((crop, data, write));
+                }
+
+                let bytes = encoded
+                    .iter()
+                    .filter(|(_, _, write)| *write)
+                    .map(|(_, data, _)| data.len() as u64)
+                    .sum();
+                if !budget.reserve(bytes) {
+                    return Err(TransformError::OverBudget);
+                }
+
+                let mut crops = Vec::new();
+                let mut post_process_error = None;
+                for (crop, data, write) in encoded {
+                    if write {
+                        fs::write(&crop.path, &data)
+                            .and_then(|_| set_mode(Path::new(&crop.path), output.file_mode))
+                            .map_err(|err| TransformError::IoError(err.to_string()))?;
+                        if let Some(cmd) = &output.post_process_cmd {
+                            if let Err(err) = run_post_process(cmd, &crop.path) {
+                                post_process_error.get_or_insert(err);
+                            }
+                        }
+                    }
+                    crops.push(crop);
+                }
+                Ok((crops, upscaled, post_process_error))
+            })
+        };
+
+    let mut report = TransformReport {
+        operations,
+        crops: completed,
+        failures: empty
+            .into_iter()
+            .map(|source| Failure {
+                source,
+                size: None,
+                reason: "empty file".to_string(),
+            })
+            .collect(),
+        ..TransformReport::default()
+    };
+    let mut pending = jobs.into_iter().flat_map(|(f, file_sizes, file_options)| {
+        file_sizes
+            .into_iter()
+            .map(move |(size, formats)| (f.to_owned(), size, formats, file_options))
+    });
+    // Spawning a batch at a time keeps the number of tasks held in memory
+    // bounded, however many files there are.
+    let batch_size = options.batch_size.map_or(usize::MAX, NonZeroUsize::get);
+    let mut counter = 0;
+    loop {
+        let tasks: Vec<(String, Size, tokio::task::JoinHandle<CropResult>)> = pending
+            .by_ref()
+            .take(batch_size)
+            .map(|(source, size, formats, file_options)| {
+                let task = spawn_crop(source.to_owned(), size, formats, file_options);
+                (source, size, task)
+            })
+            .collect();
+        if tasks.is_empty() {
+            break;
+        }
+        for (source, size, mut task) in tasks {
+            counter += 1;
+            let result = if interrupted() {
+                match tokio::time::timeout(INTERRUPT_GRACE, &mut task).await {
+                    Ok(result) => result,
+                    Err(_) => {
+                        task.abort();
+                        report.interrupted = true;
+                        continue;
+                    }
+                }
+            } else {
+                task.await
+            };
+            progress.update(counter, operations as u32, "Processing");
+            match result {
+                Ok(res) => {
+                    let (crops, upscaled, post_process_error) = match res {
+                        Ok(c) => c,
+                        Err(TransformError::Interrupted) => {
+                            report.interrupted = true;
+                            continue;
+                        }
+                        Err(TransformError::OverBudget) => {
+                            report.over_budget += 1;
+                            continue;
+                        }
+                        Err(err) => {
+                            log::error(&format!(
+                                "task result err: {} at {}x{}: {}",
+                                source, size.0, size.1, err
+                            ));
+                            report.failures.push(Failure {
+                                source,
+                                size: Some(size),
+                                reason: err.to_string(),
+                            });
+                            continue;
+                        }
+                    };
+
+                    // A failed post-process step still leaves a usable crop.
+                    if let Some(err) = post_process_error {
+                        log::error(&format!("post-process err: {}", err));
+                        report.failures.push(Failure {
+                            source,
+                            size: Some(size),
+                            reason: format!("post-process: {}", err),
+                        });
+                    }
+
+                    if let (true, Some(crop)) = (upscaled, crops.first()) {
+                        *report.undersized.entry(crop.requested).or_default() += 1;
+                    }
+                    report.crops.extend(crops);
+                }
+                Err(err) => {
+                    log::error(&format!(
+                        "task panicked: {} at {}x{}: {}",
+                        source, size.0, size.1, err
+                    ));
+                    report.failures.push(Failure {
+                        source,
+                        size: Some(size),
+                        reason: format!("task panicked: {}", err),
+                    });
+                }
+            };
+        }
+    }
 
-            tasks.push(task);
+    if output.include_originals {
+        let sources: HashSet<String> = report
+            .crops
+            .iter()
+            .map(|crop| crop.source.to_owned())
+            .collect();
+        for source in sources {
+            match copy_original(&source, output) {
+                Ok(path) => report.originals.push(path),
+                Err(err) => {
+                    log::error(&format!("failed to copy {}: {}", source, err));
+                    report.failures.push(Failure {
+                        source,
+                        size: None,
+                        reason: format!("include originals: {}", err),
+                    });
+                }
+            }
         }
+        report.originals.sort();
     }
 
-    let mut created_files = Vec::new();
-    let mut counter = 1;
-    for task in tasks.into_iter() {
-        print_list_iter_status(counter, operations as u32, "Processing", verbose);
-        match task.await {
-            Ok(res) => {
-                let path = match res {
-                    Ok(p) => p,
+    for (duplicate, original) in duplicates {
+        let linked: Vec<Crop> = report
+            .crops
+            .iter()
+            .filter(|crop| crop.source == original)
+            .filter_map(
+                |crop| match link_crop(crop, &duplicate, &locations[&duplicate], output) {
+                    Ok(linked) => Some(linked),
                     Err(err) => {
-                        println!("task result err: {}", err);
-                        continue;
+                        log::error(&format!("failed to link {}: {}", duplicate, err));
+                        report.failures.push(Failure {
+                            source: duplicate.to_owned(),
+                            size: None,
+                            reason: format!("dedupe: {}", err),
+                        });
+                        None
                     }
-                };
+                },
+            )
+            .collect();
+        report.crops.extend(linked);
+    }
+
+    report
+}
+
+/// Directory and base file name the crops of `source` are written under.
+fn crop_location(source: &str, output: &OutputOptions) -> (PathBuf, String) {
+    let relative_dir =
+        relative_source_dir(source, &output.source_root, output.strip_prefix.as_deref());
+    if output.flatten {
+        let file_name = flattened_file_name(&relative_dir, &get_file_name(source));
+        (PathBuf::from(&output.path), file_name)
+    } else {
+        (
+            Path::new(&output.path).join(relative_dir),
+            get_file_name(source),
+        )
+    }
+}
+
+/// `crop_location` of each of `sources`, except that sources which would
+/// share crop names, like `photo.jpg` and `photo.png` in one directory, keep
+/// their extension in them (`photo.jpg_200x200px_200w.jpg`), followed by a
+/// number if that still isn't unique.
+fn crop_locations<'a>(
+    sources: impl Iterator<Item = &'a String>,
+    output: &OutputOptions,
+) -> HashMap<String, (PathBuf, String)> {
+    let mut locations: HashMap<String, (PathBuf, String)> = sources
+        .map(|source| (source.to_owned(), crop_location(source, output)))
+        .collect();
+    let mut counts: HashMap<(PathBuf, String), usize> = HashMap::new();
+    for location in locations.values() {
+        *counts.entry(location.to_owned()).or_default() += 1;
+    }
+    let mut colliding: Vec<String> = locations
+        .iter()
+        .filter(|(_, location)| counts[location] > 1)
+        .map(|(source, _)| source.to_owned())
+        .collect();
+    colliding.sort();
 
-                counter += 1;
-                created_files.push(path);
+    let mut taken: HashSet<(PathBuf, String)> = counts.into_keys().collect();
+    for source in colliding {
+        let (dir, file_name) = locations[&source].to_owned();
+        let base = match Path::new(&source).extension() {
+            Some(extension) => format!("{}.{}", file_name, extension.to_string_lossy()),
+            None => file_name,
+        };
+        let mut name = base.to_owned();
+        let mut n = 2;
+        while taken.contains(&(dir.to_owned(), name.to_owned())) {
+            name = format!("{}~{}", base, n);
+            n += 1;
+        }
+        log::warn(&format!(
+            "{} shares its crop names with another source, naming them {}_...",
+            source, name
+        ));
+        taken.insert((dir.to_owned(), name.to_owned()));
+        locations.insert(source, (dir, name));
+    }
+    locations
+}
+
+/// Splits `files` into unique sources and `(duplicate, original)` pairs of
+/// sources with identical contents.
+fn partition_duplicates(files: Vec<String>) -> (Vec<String>, Vec<(String, String)>) {
+    let mut originals: HashMap<String, String> = HashMap::new();
+    let mut unique = Vec::new();
+    let mut duplicates = Vec::new();
+    for f in files {
+        let digest = match fs::read(&f) {
+            Ok(data) => format!("{:x}", md5::compute(data)),
+            Err(_) => {
+                // Let the transform report the read error.
+                unique.push(f);
+                continue;
             }
-            Err(err) => println!("task panicked: {}", err),
         };
+        match originals.get(&digest) {
+            Some(original) => duplicates.push((f, original.to_owned())),
+            None => {
+                originals.insert(digest, f.to_owned());
+                unique.push(f);
+            }
+        }
     }
+    (unique, duplicates)
+}
 
-    created_files
+/// Links the crop of `duplicate` that corresponds to `crop` to the file
+/// already written for `crop`.
+fn link_crop(
+    crop: &Crop,
+    duplicate: &str,
+    (output_dir, file_name): &(PathBuf, String),
+    output: &OutputOptions,
+) -> io::Result<Crop> {
+    let path = output_dir.join(generate_thumb_path(
+        file_name,
+        crop.width,
+        crop.height,
+        crop.hash.as_deref(),
+        crop.format.extension(),
+        output.size_label,
+    ));
+    // Inline crops have no file to link to.
+    if crop.data_uri.is_none() {
+        create_dir_with_mode(output_dir, &output.path, output.dir_mode)?;
+        if path.symlink_metadata().is_ok() {
+            fs::remove_file(&path)?;
+        }
+        link_file(&fs::canonicalize(&crop.path)?, &path)?;
+    }
+
+    Ok(Crop {
+        source: duplicate.to_owned(),
+        requested: crop.requested,
+        format: crop.format,
+        path: path.to_string_lossy().into_owned(),
+        width: crop.width,
+        height: crop.height,
+        hash: crop.hash.to_owned(),
+        data_uri: crop.data_uri.to_owned(),
+    })
 }
 
-#[derive(Debug)]
+/// Hard-links, or failing that copies, `source` into the directory its crops
+/// are written to. Returns the path of the copy.
+fn copy_original(source: &str, output: &OutputOptions) -> io::Result<String> {
+    let (output_dir, file_name) = crop_location(source, output);
+    let name = match Path::new(source).extension() {
+        Some(extension) => format!("{}.{}", file_name, extension.to_string_lossy()),
+        None => file_name,
+    };
+    let path = output_dir.join(name);
+    // Writing crops next to the sources: the original is already there.
+    if path.exists() && fs::canonicalize(&path)? == fs::canonicalize(source)? {
+        return Ok(path.to_string_lossy().into_owned());
+    }
+    create_dir_with_mode(&output_dir, &output.path, output.dir_mode)?;
+    if path.symlink_metadata().is_ok() {
+        fs::remove_file(&path)?;
+    }
+    if fs::hard_link(source, &path).is_err() {
+        fs::copy(source, &path)?;
+    }
+    Ok(path.to_string_lossy().into_owned())
+}
+
+#[cfg(unix)]
+fn link_file(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(not(unix))]
+fn link_file(target: &Path, link: &Path) -> io::Result<()> {
+    fs::copy(target, link).map(|_| ())
+}
+
+/// Dimensions and content hash of the crop already at `path`.
+fn existing_crop(
+    path: &str,
+    content_hash: Option<ContentHash>,
+) -> Result<(u32, u32, Option<String>), TransformError> {
+    let data =
+        fs::read(path).map_err(|err| TransformError::IoError(format!("{}: {}", path, err)))?;
+    let (width, height) = ImageReader::new(Cursor::new(&data))
+        .with_guessed_format()
+        .map_err(|err| TransformError::IoError(format!("{}: {}", path, err)))?
+        .into_dimensions()
+        .map_err(|err| TransformError::RasterError(format!("{}: {}", path, err)))?;
+    Ok((
+        width,
+        height,
+        content_hash.map(|content_hash| hash_bytes(&data, content_hash)),
+    ))
+}
+
+/// Whether `path` looks like an image, judging by its magic bytes and
+/// falling back to its extension.
+fn is_image(path: &str) -> bool {
+    match ImageReader::open(path).and_then(|reader| reader.with_guessed_format()) {
+        Ok(reader) => reader.format().is_some() || is_heif_file(path),
+        Err(_) => false,
+    }
+}
+
+/// Whether the longest edge of the image at `path` is shorter than `min`.
+/// Sources whose dimensions can't be read are left for the decoder to report.
+fn is_too_small(path: &str, min: u32) -> bool {
+    match ImageReader::open(path)
+        .and_then(|reader| reader.with_guessed_format())
+        .map(|reader| reader.into_dimensions())
+    {
+        Ok(Ok((width, height))) => width.max(height) < min,
+        _ => false,
+    }
+}
+
+/// HEIC/HEIF files are ISO BMFF containers with one of these major brands.
+const HEIF_BRANDS: [&[u8]; 6] = [b"heic", b"heix", b"hevc", b"hevx", b"heim", b"mif1"];
+
+fn is_heif(data: &[u8]) -> bool {
+    data.len() >= 12 && &data[4..8] == b"ftyp" && HEIF_BRANDS.contains(&&data[8..12])
+}
+
+fn is_heif_file(path: &str) -> bool {
+    let mut header = [0; 12];
+    match fs::File::open(path).and_then(|mut f| f.read_exact(&mut header)) {
+        Ok(()) => is_heif(&header),
+        Err(_) => false,
+    }
+}
+
+#[derive(Debug, Clone)]
+#[allow(clippy::enum_variant_names)]
 pub enum TransformError {
     RasterError(String),
+    EncodeError(String),
+    IoError(String),
+    Interrupted,
+    OverBudget,
+}
+
+impl fmt::Display for TransformError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransformError::RasterError(err) => write!(f, "raster error: {}", err),
+            TransformError::EncodeError(err) => write!(f, "encode error: {}", err),
+            TransformError::IoError(err) => write!(f, "io error: {}", err),
+            TransformError::Interrupted => write!(f, "interrupted"),
+            TransformError::OverBudget => write!(f, "output budget exceeded"),
+        }
+    }
+}
+
+/// Decodes the source from memory and resizes it. `transform_images` reads
+/// files through a `DecodeCache` instead, to decode each source once for all
+/// its sizes.
+pub fn transform_image_bytes(
+    data: &[u8],
+    options: &TransformOptions,
+) -> Result<DynamicImage, TransformError> {
+    decode_image_bytes(data, options).map(|image| resize_image(&image, options))
 }
 
-fn transform_image(
+/// Decodes, rotates and flips the source at `path`, without resizing it.
+pub fn decode_image(
     path: &str,
+    options: &TransformOptions,
+) -> Result<DynamicImage, TransformError> {
+    let data = fs::read(path).map_err(|err| TransformError::RasterError(err.to_string()))?;
+    decode_image_bytes(&data, options)
+}
+
+fn decode_image_bytes(
+    data: &[u8],
+    options: &TransformOptions,
+) -> Result<DynamicImage, TransformError> {
+    if is_heif(data) {
+        // The image crate can't decode HEVC-coded images.
+        return Err(TransformError::RasterError(
+            "HEIC/HEIF decoding is not supported".to_string(),
+        ));
+    }
+    let mut image_loader = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .map_err(|err| TransformError::RasterError(err.to_string()))?;
+    image_loader.limits(decode_limits(options));
+    let image = match image_loader.decode() {
+        Ok(i) => i,
+        Err(ImageError::Limits(err)) => {
+            return Err(TransformError::RasterError(format!(
+                "image exceeds decode limits: {}",
+                err
+            )))
+        }
+        Err(err) => return Err(TransformError::RasterError(err.to_string())),
+    };
+    let image = flip_image(rotate_image(image, options.rotate), options.flip);
+    Ok(match options.trim {
+        Some(tolerance) => trim_image(image, tolerance),
+        None => image,
+    })
+}
+
+/// Crops away the uniform border around `image`, like ImageMagick's `-trim`:
+/// rows and columns along the edges whose pixels all match the top-left
+/// pixel to within `tolerance` per channel. A uniform image is left as is.
+fn trim_image(image: DynamicImage, tolerance: u8) -> DynamicImage {
+    let pixels = image.to_rgba8();
+    let (width, height) = pixels.dimensions();
+    let corner = *pixels.get_pixel(0, 0);
+    let matches = |x: u32, y: u32| {
+        pixels
+            .get_pixel(x, y)
+            .0
+            .iter()
+            .zip(corner.0)
+            .all(|(a, b)| a.abs_diff(b) <= tolerance)
+    };
+    let row_matches = |y: u32| (0..width).all(|x| matches(x, y));
+    let column_matches =
+        |x: u32, rows: std::ops::Range<u32>| rows.into_iter().all(|y| matches(x, y));
+
+    let top = match (0..height).find(|y| !row_matches(*y)) {
+        Some(top) => top,
+        None => return image,
+    };
+    let bottom = (top..height)
+        .rev()
+        .find(|y| !row_matches(*y))
+        .unwrap_or(top)
+        + 1;
+    let left = (0..width)
+        .find(|x| !column_matches(*x, top..bottom))
+        .unwrap_or(0);
+    let right = (left..width)
+        .rev()
+        .find(|x| !column_matches(*x, top..bottom))
+        .unwrap_or(left)
+        + 1;
+    image.crop_imm(left, top, right - left, bottom - top)
+}
+
+fn decode_limits(options: &TransformOptions) -> Limits {
+    let mut limits = Limits::default();
+    if let Some(max_image_bytes) = options.max_image_bytes {
+        limits.max_alloc = Some(max_image_bytes);
+    }
+    limits.max_image_width = options.max_image_dimension;
+    limits.max_image_height = options.max_image_dimension;
+    limits
+}
+
+fn rotate_image(image: DynamicImage, rotation: Rotation) -> DynamicImage {
+    match rotation {
+        Rotation::None => image,
+        Rotation::Rotate90 => image.rotate90(),
+        Rotation::Rotate180 => image.rotate180(),
+        Rotation::Rotate270 => image.rotate270(),
+    }
+}
+
+fn flip_image(image: DynamicImage, flip: Flip) -> DynamicImage {
+    match flip {
+        Flip::None => image,
+        Flip::Horizontal => image.fliph(),
+        Flip::Vertical => image.flipv(),
+        Flip::Both => image.rotate180(),
+    }
+}
+
+/// Whether cropping a `source` of these dimensions to the requested size
+/// means enlarging it.
+fn needs_upscale((width, height): (u32, u32), options: &TransformOptions) -> bool {
+    let (mut wanted_width, mut wanted_height) = (options.width, options.height);
+    if options.orientation_swap && (width < height) != (wanted_width < wanted_height) {
+        std::mem::swap(&mut wanted_width, &mut wanted_height);
+    }
+    width < wanted_width || height < wanted_height
+}
+
+fn resize_image(image: &DynamicImage, options: &TransformOptions) -> DynamicImage {
+    if options.downscale_only && needs_upscale(image.dimensions(), options) {
+        return image.clone();
+    }
+    let (mut width, mut height, filter) = (options.width, options.height, options.filter);
+    if options.orientation_swap && (image.width() < image.height()) != (width < height) {
+        std::mem::swap(&mut width, &mut height);
+    }
+    if let Some(aspect) = options.crop_aspect {
+        let (width, height) = aspect_box(image.dimensions(), aspect, width.max(height));
+        return fill(image, width, height, filter, options.gravity);
+    }
+    match options.resize_mode {
+        ResizeMode::Fill => fill(image, width, height, filter, options.gravity),
+        ResizeMode::Fit => image.resize(width, height, filter),
+        ResizeMode::Exact => image.resize_exact(width, height, filter),
+    }
+}
+
+/// Scales and crops `image` to exactly `width` x `height`, placing the crop
+/// window according to `gravity`.
+fn fill(
+    image: &DynamicImage,
     width: u32,
     height: u32,
-) -> Result<image::DynamicImage, TransformError> {
-    let image_loader = match ImageReader::open(path) {
-        Ok(i) => i,
-        Err(err) => {
-            print!("err open: {:?}", err);
-            return Err(TransformError::RasterError(err.to_string()));
+    filter: FilterType,
+    gravity: Gravity,
+) -> DynamicImage {
+    if gravity == Gravity::Center {
+        return image.resize_to_fill(width, height, filter);
+    }
+    let (source_width, source_height) = image.dimensions();
+    let (crop_width, crop_height) = aspect_box(
+        (source_width, source_height),
+        AspectRatio(width, height),
+        u32::MAX,
+    );
+    let (columns, rows) = edge_profile(image);
+    let total: f64 = columns.iter().sum();
+    let flat = total / ((columns.len() * rows.len()).max(1) as f64) < EDGE_MIN_MEAN;
+    let (x, y) = if flat {
+        (
+            (source_width - crop_width) / 2,
+            (source_height - crop_height) / 2,
+        )
+    } else if crop_width < source_width {
+        (window_offset(&columns, source_width, crop_width), 0)
+    } else {
+        (0, window_offset(&rows, source_height, crop_height))
+    };
+    image
+        .crop_imm(x, y, crop_width, crop_height)
+        .resize_exact(width, height, filter)
+}
+
+/// Longest edge of the copy edges are detected on.
+const EDGE_SAMPLE_SIZE: u32 = 96;
+/// Share of the edge signal the busiest third needs before the crop leaves
+/// the center; a featureless image has a third in each.
+const EDGE_BIAS_SHARE: f64 = 0.45;
+/// Mean edge strength per sample pixel below which an image counts as flat
+/// and is cropped in the middle.
+const EDGE_MIN_MEAN: f64 = 4.0;
+
+/// Sums of Sobel gradient magnitudes over the columns and rows of a small
+/// greyscale copy of `image`.
+fn edge_profile(image: &DynamicImage) -> (Vec<f64>, Vec<f64>) {
+    let sample = image
+        .thumbnail(EDGE_SAMPLE_SIZE, EDGE_SAMPLE_SIZE)
+        .to_luma8();
+    let (width, height) = sample.dimensions();
+    let mut columns = vec![0.0; width as usize];
+    let mut rows = vec![0.0; height as usize];
+    let at = |x: u32, y: u32| sample.get_pixel(x, y).0[0] as i32;
+    for y in 1..height.saturating_sub(1) {
+        for x in 1..width.saturating_sub(1) {
+            let gx = at(x + 1, y - 1) + 2 * at(x + 1, y) + at(x + 1, y + 1)
+                - at(x - 1, y - 1)
+                - 2 * at(x - 1, y)
+                - at(x - 1, y + 1);
+            let gy = at(x - 1, y + 1) + 2 * at(x, y + 1) + at(x + 1, y + 1)
+                - at(x - 1, y - 1)
+                - 2 * at(x, y - 1)
+                - at(x + 1, y - 1);
+            let magnitude = (gx.abs() + gy.abs()) as f64;
+            columns[x as usize] += magnitude;
+            rows[y as usize] += magnitude;
         }
+    }
+    (columns, rows)
+}
+
+/// Offset of a `window` long crop along an axis `length` long, centred on the
+/// third of `profile` with the most edges, or on the middle when the edges
+/// are evenly spread.
+fn window_offset(profile: &[f64], length: u32, window: u32) -> u32 {
+    let centered = (length - window) / 2;
+    let total: f64 = profile.iter().sum();
+    if profile.len() < 3 || total == 0.0 {
+        return centered;
+    }
+    let thirds: Vec<f64> = (0..3)
+        .map(|third| {
+            let start = profile.len() * third / 3;
+            let end = profile.len() * (third + 1) / 3;
+            profile[start..end].iter().sum()
+        })
+        .collect();
+    let (busiest, share) = thirds
+        .iter()
+        .enumerate()
+        .map(|(third, sum)| (third, sum / total))
+        .fold(
+            (1, 0.0),
+            |best, third| if third.1 > best.1 { third } else { best },
+        );
+    if busiest == 1 || share < EDGE_BIAS_SHARE {
+        return centered;
+    }
+    let focus = (length as u64 * (2 * busiest as u64 + 1) / 6) as u32;
+    focus.saturating_sub(window / 2).min(length - window)
+}
+
+/// The largest `aspect` box that fits inside `source`, scaled down so its
+/// longest edge is at most `max_edge`.
+fn aspect_box(source: (u32, u32), aspect: AspectRatio, max_edge: u32) -> (u32, u32) {
+    let (sw, sh) = (source.0 as u64, source.1 as u64);
+    let (aw, ah) = (aspect.0 as u64, aspect.1 as u64);
+    let (width, height) = if sw * ah >= sh * aw {
+        (sh * aw / ah, sh)
+    } else {
+        (sw, sw * ah / aw)
     };
-    let image = match image_loader.decode() {
-        Ok(i) => i,
-        Err(err) => return Err(TransformError::RasterError(err.to_string())),
+
+    let max_edge = max_edge as u64;
+    let (width, height) = if width.max(height) <= max_edge {
+        (width, height)
+    } else if width >= height {
+        (max_edge, height * max_edge / width)
+    } else {
+        (width * max_edge / height, max_edge)
     };
-    Ok(image.resize_to_fill(width, height, image::imageops::FilterType::Triangle))
+    (width.max(1) as u32, height.max(1) as u32)
+}
+
+/// Encodes `image` into the format given by `extension` and writes it to
+/// `output_path`, named after `file_name` and the image dimensions.
+/// With `content_hash` set, a short hash of the encoded bytes is spliced into
+/// the name so unchanged output keeps a stable name. `icc_profile` applies to
+/// JPEG output only.
+///
+/// The encoded bytes depend only on the image and the options: none of the
+/// encoders write timestamps, and no source metadata is carried over except
+/// the ICC profile with `--preserve-icc`, which comes from the source bytes
+/// themselves. Identical input always gives identical output.
+///
+/// Returns the written path and the hash, if any.
+#[allow(clippy::too_many_arguments)]
+pub fn save_image(
+    image: &DynamicImage,
+    output_path: &str,
+    file_name: &str,
+    extension: &str,
+    encode: &EncodeOptions,
+    icc_profile: Option<&[u8]>,
+    content_hash: Option<ContentHash>,
+    size_label: SizeLabel,
+) -> Result<(String, Option<String>), TransformError> {
+    let (data, hash) = encode_crop(image, extension, encode, icc_profile, content_hash)?;
+    let path = crop_path(
+        output_path,
+        file_name,
+        image,
+        hash.as_deref(),
+        extension,
+        size_label,
+    );
+    fs::write(&path, &data).map_err(|err| TransformError::IoError(err.to_string()))?;
+
+    Ok((path, hash))
 }
 
-pub fn save_image(image: &image::DynamicImage, path: &str) {
-    image.save(path).expect("failed to save image")
+/// Encodes `image` as `save_image` does, returning the encoded bytes and
+/// their hash, if any, without writing them.
+pub fn encode_crop(
+    image: &DynamicImage,
+    extension: &str,
+    encode: &EncodeOptions,
+    icc_profile: Option<&[u8]>,
+    content_hash: Option<ContentHash>,
+) -> Result<(Vec<u8>, Option<String>), TransformError> {
+    let format = ImageFormat::from_extension(extension).ok_or_else(|| {
+        TransformError::EncodeError(format!("unsupported output format '{}'", extension))
+    })?;
+
+    let mut data = encode_image(image, format, encode)
+        .map_err(|err| TransformError::EncodeError(err.to_string()))?;
+
+    if format == ImageFormat::Jpeg && encode.strip_metadata {
+        data = strip_jpeg_metadata(&data);
+    }
+    match (format, encode.dpi) {
+        (ImageFormat::Jpeg, Some(dpi)) => data = set_jpeg_density(&data, dpi),
+        (ImageFormat::Png, Some(dpi)) => data = set_png_density(&data, dpi),
+        _ => {}
+    }
+    if let (ImageFormat::Jpeg, Some(profile)) = (format, icc_profile) {
+        data = embed_jpeg_icc(&data, profile);
+    }
+
+    let hash = content_hash.map(|content_hash| hash_bytes(&data, content_hash));
+    Ok((data, hash))
+}
+
+/// Path `save_image` writes the crop `image` of `file_name` to.
+fn crop_path(
+    output_path: &str,
+    file_name: &str,
+    image: &DynamicImage,
+    hash: Option<&str>,
+    extension: &str,
+    size_label: SizeLabel,
+) -> String {
+    format!(
+        "{}/{}",
+        output_path,
+        generate_thumb_path(
+            file_name,
+            image.width(),
+            image.height(),
+            hash,
+            extension,
+            size_label
+        )
+    )
+}
+
+/// A quality that gives good results on each lossy codec's own scale.
+pub fn default_quality(format: ImageFormat) -> u8 {
+    match format {
+        ImageFormat::Jpeg => 82,
+        ImageFormat::WebP => 80,
+        ImageFormat::Avif => 50,
+        _ => 100,
+    }
+}
+
+fn encode_image(
+    image: &DynamicImage,
+    format: ImageFormat,
+    encode: &EncodeOptions,
+) -> image::ImageResult<Vec<u8>> {
+    let mut data = Cursor::new(Vec::new());
+    match format {
+        ImageFormat::Jpeg => {
+            let quality = encode.quality.unwrap_or_else(|| default_quality(format));
+            JpegEncoder::new_with_quality(&mut data, quality).encode_image(image)?
+        }
+        ImageFormat::Png if encode.quantize_colors.is_some() => {
+            return encode_indexed_png(image, encode).map_err(|err| {
+                ImageError::Encoding(EncodingError::new(ImageFormat::Png.into(), err))
+            });
+        }
+        ImageFormat::Png => {
+            PngEncoder::new_with_quality(&mut data, encode.png_compression, encode.png_filter)
+                .write_image(
+                    image.as_bytes(),
+                    image.width(),
+                    image.height(),
+                    image.color(),
+                )?
+        }
+        format => image.write_to(&mut data, ImageOutputFormat::from(format))?,
+    }
+    Ok(data.into_inner())
+}
+
+/// Encodes `image` as a palette PNG of at most `encode.quantize_colors`
+/// colours, picked with NeuQuant. Much smaller than truecolour for small
+/// crops, at little visible cost.
+fn encode_indexed_png(
+    image: &DynamicImage,
+    encode: &EncodeOptions,
+) -> Result<Vec<u8>, png::EncodingError> {
+    let colors = encode.quantize_colors.unwrap_or(256) as usize;
+    let pixels = image.to_rgba8();
+    let quantizer = NeuQuant::new(QUANTIZER_SAMPLE_FACTOR, colors, pixels.as_raw());
+    let indices: Vec<u8> = pixels
+        .pixels()
+        .map(|pixel| quantizer.index_of(&pixel.0) as u8)
+        .collect();
+    let palette = quantizer.color_map_rgba();
+
+    let mut data = Vec::new();
+    let mut encoder = png::Encoder::new(&mut data, image.width(), image.height());
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_palette(
+        palette
+            .chunks(4)
+            .flat_map(|color| color[..3].to_vec())
+            .collect::<Vec<u8>>(),
+    );
+    if palette.chunks(4).any(|color| color[3] < u8::MAX) {
+        encoder.set_trns(palette.chunks(4).map(|color| color[3]).collect::<Vec<u8>>());
+    }
+    encoder.set_compression(match encode.png_compression {
+        CompressionType::Fast => png::Compression::Fast,
+        CompressionType::Best => png::Compression::Best,
+        _ => png::Compression::Default,
+    });
+    // Filtering rarely helps palette images.
+    encoder.set_filter(png::FilterType::NoFilter);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&indices)?;
+    writer.finish()?;
+    Ok(data)
+}
+
+fn hash_bytes(data: &[u8], content_hash: ContentHash) -> String {
+    let digest = match content_hash.algorithm {
+        HashAlgorithm::Md5 => format!("{:x}", md5::compute(data)),
+        HashAlgorithm::Sha256 => ring::digest::digest(&ring::digest::SHA256, data)
+            .as_ref()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect(),
+        HashAlgorithm::Crc32 => format!("{:08x}", crc32fast::hash(data)),
+    };
+    digest[..content_hash.length.min(digest.len())].to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cutter::metadata::has_jpeg_metadata;
+
+    fn fixture(name: &str) -> String {
+        format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    fn options(width: u32, height: u32) -> TransformOptions {
+        TransformOptions {
+            width,
+            height,
+            filter: FilterType::Triangle,
+            resize_mode: ResizeMode::Fill,
+            rotate: Rotation::None,
+            flip: Flip::None,
+            preserve_icc: false,
+            min_source_dimension: None,
+            crop_aspect: None,
+            orientation_swap: false,
+            max_image_bytes: None,
+            max_image_dimension: None,
+            decode_cache_size: 0,
+            trim: None,
+            downscale_only: false,
+            gravity: Gravity::Center,
+            batch_size: None,
+            scale: None,
+        }
+    }
+
+    fn transform_image(
+        path: &str,
+        options: &TransformOptions,
+    ) -> Result<DynamicImage, TransformError> {
+        decode_image(path, options).map(|image| resize_image(&image, options))
+    }
+
+    fn output_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cutter-test-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Average hash: one bit per pixel of an 8x8 greyscale thumbnail, set
+    /// when the pixel is brighter than the mean.
+    fn average_hash(image: &DynamicImage) -> u64 {
+        let small = image.resize_exact(8, 8, FilterType::Triangle).to_luma8();
+        let mean = small.pixels().map(|p| p.0[0] as u32).sum::<u32>() / 64;
+        small
+            .pixels()
+            .enumerate()
+            .filter(|(_, p)| p.0[0] as u32 > mean)
+            .fold(0, |hash, (i, _)| hash | 1 << i)
+    }
+
+    /// Crops `name` to `width`x`height`, saves it as JPEG and decodes the
+    /// written file again.
+    fn crop_fixture(name: &str, width: u32, height: u32) -> DynamicImage {
+        let image = transform_image(&fixture(name), &options(width, height)).unwrap();
+        let dir = output_dir(name);
+        let (path, _) = save_image(
+            &image,
+            &dir.to_string_lossy(),
+            "crop",
+            "jpg",
+            &EncodeOptions::default(),
+            None,
+            None,
+            SizeLabel::Plain,
+        )
+        .unwrap();
+
+        let reader = ImageReader::open(&path)
+            .unwrap()
+            .with_guessed_format()
+            .unwrap();
+        assert_eq!(reader.format(), Some(ImageFormat::Jpeg));
+        let output = reader.decode().unwrap();
+        fs::remove_dir_all(dir).unwrap();
+        output
+    }
+
+    fn assert_similar(image: &DynamicImage, golden: u64) {
+        let distance = (average_hash(image) ^ golden).count_ones();
+        assert!(
+            distance <= 4,
+            "hash {:#018x} is {} bits from golden {:#018x}",
+            average_hash(image),
+            distance,
+            golden
+        );
+    }
+
+    #[test]
+    fn crops_portrait() {
+        let crop = crop_fixture("portrait.png", 40, 40);
+        assert_eq!((crop.width(), crop.height()), (40, 40));
+        assert_similar(&crop, 0xfffffef8e0800000);
+    }
+
+    #[test]
+    fn crops_landscape() {
+        let crop = crop_fixture("landscape.jpg", 32, 18);
+        assert_eq!((crop.width(), crop.height()), (32, 18));
+        assert_similar(&crop, 0xfece8e84848cc8f8);
+    }
+
+    #[test]
+    fn crops_png_with_alpha_to_jpeg() {
+        let crop = crop_fixture("alpha.png", 20, 20);
+        assert_eq!((crop.width(), crop.height()), (20, 20));
+        assert_similar(&crop, 0xf0f0f0f0f0f0f0f0);
+    }
+
+    #[test]
+    fn crops_cmyk_jpeg() {
+        let crop = crop_fixture("cmyk.jpg", 24, 16);
+        assert_eq!((crop.width(), crop.height()), (24, 16));
+        assert_similar(&crop, 0x3f3f3f1f0f0f0f0f);
+    }
+
+    #[test]
+    fn aspect_box_fits_source_and_cap() {
+        let wide = AspectRatio(16, 9);
+        assert_eq!(aspect_box((4000, 3000), wide, 10_000), (4000, 2250));
+        assert_eq!(aspect_box((4000, 3000), wide, 1600), (1600, 900));
+        assert_eq!(aspect_box((1000, 3000), wide, 10_000), (1000, 562));
+        assert_eq!(aspect_box((300, 200), AspectRatio(1, 1), 100), (100, 100));
+    }
+
+    #[test]
+    fn orientation_swap_gives_portraits_portrait_crops() {
+        let options = TransformOptions {
+            orientation_swap: true,
+            ..options(32, 18)
+        };
+        let crop = transform_image(&fixture("portrait.png"), &options).unwrap();
+        assert_eq!((crop.width(), crop.height()), (18, 32));
+
+        let crop = transform_image(&fixture("landscape.jpg"), &options).unwrap();
+        assert_eq!((crop.width(), crop.height()), (32, 18));
+    }
+
+    /// Inserts an EXIF segment with a GPS IFD pointer after the SOI marker.
+    fn with_exif(jpeg: &[u8]) -> Vec<u8> {
+        let mut exif = b"Exif\0\0MM\0\x2a\0\0\0\x08\0\x01".to_vec();
+        exif.extend_from_slice(&[0x88, 0x25, 0, 4, 0, 0, 0, 1, 0, 0, 0, 0x1a, 0, 0, 0, 0]);
+        let mut out = jpeg[..2].to_vec();
+        out.extend_from_slice(&[0xFF, 0xE1]);
+        out.extend_from_slice(&((exif.len() + 2) as u16).to_be_bytes());
+        out.extend_from_slice(&exif);
+        out.extend_from_slice(&jpeg[2..]);
+        out
+    }
+
+    #[test]
+    fn strips_exif_and_gps_from_output() {
+        let source = with_exif(&fs::read(fixture("landscape.jpg")).unwrap());
+        assert!(has_jpeg_metadata(&source));
+
+        let image = transform_image_bytes(&source, &options(32, 18)).unwrap();
+        let (data, _) = encode_crop(&image, "jpg", &EncodeOptions::default(), None, None).unwrap();
+        assert!(!has_jpeg_metadata(&data));
+        assert!(!data.windows(4).any(|w| w == b"Exif"));
+
+        assert!(!has_jpeg_metadata(&strip_jpeg_metadata(&source)));
+    }
+
+    #[test]
+    fn flips_sources() {
+        let path = fixture("landscape.jpg");
+        let source = decode_image(&path, &options(0, 0)).unwrap().to_rgb8();
+        let (width, height) = source.dimensions();
+        let flip = |flip| {
+            let options = TransformOptions {
+                flip,
+                ..options(0, 0)
+            };
+            decode_image(&path, &options).unwrap().to_rgb8()
+        };
+        assert_eq!(
+            flip(Flip::Horizontal).get_pixel(0, 0),
+            source.get_pixel(width - 1, 0)
+        );
+        assert_eq!(
+            flip(Flip::Vertical).get_pixel(0, 0),
+            source.get_pixel(0, height - 1)
+        );
+        assert_eq!(
+            flip(Flip::Both).get_pixel(0, 0),
+            source.get_pixel(width - 1, height - 1)
+        );
+    }
+
+    #[test]
+    fn writes_output_dpi() {
+        let image = transform_image(&fixture("landscape.jpg"), &options(32, 18)).unwrap();
+        let encode = EncodeOptions {
+            dpi: Some(300),
+            ..EncodeOptions::default()
+        };
+
+        let (jpeg, _) = encode_crop(&image, "jpg", &encode, None, None).unwrap();
+        let (marker, jfif) = crate::cutter::icc::jpeg_segments(&jpeg).next().unwrap();
+        assert_eq!(marker, 0xE0);
+        assert_eq!(&jfif[7..12], &[1, 1, 44, 1, 44]);
+
+        let (png, _) = encode_crop(&image, "png", &encode, None, None).unwrap();
+        let mut phys = b"pHYs".to_vec();
+        phys.extend_from_slice(&11811u32.to_be_bytes());
+        assert!(png.windows(phys.len()).any(|w| w == phys));
+        assert_eq!(image::load_from_memory(&png).unwrap().width(), 32);
+    }
+
+    #[test]
+    fn downscale_only_keeps_small_sources() {
+        let source = transform_image(&fixture("landscape.jpg"), &options(90, 60)).unwrap();
+        let downscale_only = |width, height| TransformOptions {
+            downscale_only: true,
+            ..options(width, height)
+        };
+        assert_eq!(
+            resize_image(&source, &downscale_only(1000, 1000)).dimensions(),
+            (90, 60)
+        );
+        assert_eq!(
+            resize_image(&source, &downscale_only(30, 30)).dimensions(),
+            (30, 30)
+        );
+    }
+
+    #[test]
+    fn trim_removes_uniform_borders() {
+        let mut pixels = image::RgbImage::from_pixel(40, 30, image::Rgb([250, 250, 250]));
+        for (x, y, pixel) in pixels.enumerate_pixels_mut() {
+            if (5..25).contains(&x) && (8..20).contains(&y) {
+                *pixel = image::Rgb([10, 20, 30]);
+            }
+        }
+        // Scanner noise within the tolerance is still border.
+        pixels.put_pixel(39, 29, image::Rgb([247, 250, 252]));
+
+        let trimmed = trim_image(DynamicImage::ImageRgb8(pixels), 4);
+        assert_eq!(trimmed.dimensions(), (20, 12));
+
+        let uniform = DynamicImage::ImageRgb8(image::RgbImage::new(8, 8));
+        assert_eq!(trim_image(uniform, 0).dimensions(), (8, 8));
+    }
+
+    #[test]
+    fn auto_gravity_follows_detail() {
+        // Flat grey, with a checkerboard in the right third.
+        let mut pixels = image::RgbImage::from_pixel(300, 100, image::Rgb([128, 128, 128]));
+        for (x, y, pixel) in pixels.enumerate_pixels_mut() {
+            if x >= 220 && (x / 4 + y / 4) % 2 == 0 {
+                *pixel = image::Rgb([0, 0, 0]);
+            }
+        }
+        let image = DynamicImage::ImageRgb8(pixels);
+        let filter = FilterType::Triangle;
+
+        // Dark pixels only appear where the checkerboard was kept.
+        let auto = fill(&image, 100, 100, filter, Gravity::Auto).to_luma8();
+        assert!(auto.pixels().any(|pixel| pixel.0[0] < 64));
+        let center = fill(&image, 100, 100, filter, Gravity::Center).to_luma8();
+        assert!(center.pixels().all(|pixel| pixel.0[0] > 64));
+
+        let flat = DynamicImage::ImageRgb8(image::RgbImage::new(300, 100));
+        assert_eq!(
+            fill(&flat, 100, 100, filter, Gravity::Auto),
+            flat.resize_to_fill(100, 100, filter)
+        );
+    }
+
+    #[test]
+    fn content_hashes_use_the_chosen_algorithm() {
+        let hash = |algorithm, length| hash_bytes(b"abc", ContentHash { algorithm, length });
+        assert_eq!(hash(HashAlgorithm::Md5, 8), "90015098");
+        assert_eq!(hash(HashAlgorithm::Sha256, 12), "ba7816bf8f01");
+        assert_eq!(hash(HashAlgorithm::Crc32, 8), "352441c2");
+    }
+
+    #[test]
+    fn quantized_png_uses_a_palette() {
+        let image = transform_image(&fixture("landscape.jpg"), &options(64, 36)).unwrap();
+        let encode = |quantize_colors| {
+            let encode = EncodeOptions {
+                quantize_colors,
+                ..EncodeOptions::default()
+            };
+            encode_crop(&image, "png", &encode, None, None).unwrap().0
+        };
+        let truecolor = encode(None);
+        let indexed = encode(Some(16));
+        // Colour type 3 in the IHDR chunk marks a palette image.
+        assert_eq!(indexed[25], 3);
+        assert!(indexed.len() < truecolor.len());
+
+        let decoded = image::load_from_memory(&indexed).unwrap().to_rgb8();
+        let colors: HashSet<[u8; 3]> = decoded.pixels().map(|pixel| pixel.0).collect();
+        assert!(colors.len() <= 16);
+    }
+
+    #[test]
+    fn encoding_is_deterministic() {
+        for extension in ["jpg", "png"] {
+            let encode = |strip_metadata| {
+                let image = transform_image(&fixture("landscape.jpg"), &options(32, 18)).unwrap();
+                let encode = EncodeOptions {
+                    strip_metadata,
+                    ..EncodeOptions::default()
+                };
+                encode_crop(
+                    &image,
+                    extension,
+                    &encode,
+                    None,
+                    Some(ContentHash::default()),
+                )
+                .unwrap()
+            };
+            assert_eq!(encode(true), encode(true), "{}", extension);
+            assert_eq!(encode(false), encode(false), "{}", extension);
+        }
+    }
+
+    #[test]
+    fn rejects_images_over_decode_limits() {
+        let options = TransformOptions {
+            max_image_dimension: Some(16),
+            ..options(8, 8)
+        };
+        let err = transform_image(&fixture("landscape.jpg"), &options).unwrap_err();
+        assert!(err.to_string().contains("decode limits"), "{}", err);
+    }
 }
@@ -0,0 +1,65 @@
+//! Levelled log lines, coloured when printing to an interactive terminal.
+//!
+//! Lines go to stdout unless `to_stderr` was called, as with `--output json`,
+//! where stdout is kept for the summary. Colours are disabled when that
+//! stream isn't a terminal or `NO_COLOR` is set (see <https://no-color.org>).
+
+use std::env;
+use std::io::{self, IsTerminal};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+
+const RESET: &str = "\x1b[0m";
+
+static TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Sends log and progress lines to stderr from now on. Call before logging
+/// anything, as whether to colour them is only decided once.
+pub fn to_stderr() {
+    TO_STDERR.store(true, Ordering::Relaxed);
+}
+
+fn use_stderr() -> bool {
+    TO_STDERR.load(Ordering::Relaxed)
+}
+
+fn use_color() -> bool {
+    static USE_COLOR: OnceLock<bool> = OnceLock::new();
+    *USE_COLOR.get_or_init(|| {
+        let terminal = if use_stderr() {
+            io::stderr().is_terminal()
+        } else {
+            io::stdout().is_terminal()
+        };
+        env::var_os("NO_COLOR").is_none_or(|v| v.is_empty()) && terminal
+    })
+}
+
+/// Prints `msg` as is, for progress and other lines without a level.
+pub fn plain(msg: &str) {
+    if use_stderr() {
+        eprintln!("{}", msg);
+    } else {
+        println!("{}", msg);
+    }
+}
+
+fn print(level: &str, color: &str, msg: &str) {
+    if use_color() {
+        plain(&format!("{}{}{} {}", color, level, RESET, msg));
+    } else {
+        plain(&format!("{} {}", level, msg));
+    }
+}
+
+pub fn info(msg: &str) {
+    print("INFO", "\x1b[32m", msg);
+}
+
+pub fn warn(msg: &str) {
+    print("WARN", "\x1b[33m", msg);
+}
+
+pub fn error(msg: &str) {
+    print("ERROR", "\x1b[31m", msg);
+}
@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::iter::Peekable;
+use std::str::Chars;
+
+use super::imageprocessing::{str_to_output_format, str_to_size, Crop, OutputFormat};
+use super::util::json_string;
+
+/// Writes a JSON manifest listing every crop, mapping each source file to
+/// the output path, format, dimensions and content hash it produced. The
+/// formats of a source's crop at one size are listed next to each other.
+pub fn write_manifest(path: &str, crops: &[Crop]) -> io::Result<()> {
+    let mut crops: Vec<&Crop> = crops.iter().collect();
+    crops.sort_by(|a, b| {
+        (&a.source, a.requested.0, a.requested.1).cmp(&(&b.source, b.requested.0, b.requested.1))
+    });
+    let entries: Vec<String> = crops.into_iter().map(crop_to_json).collect();
+    let manifest = format!("[\n{}\n]\n", entries.join(",\n"));
+    // Written next to the manifest and renamed over it, so a crash leaves
+    // the previous manifest rather than half of this one.
+    let partial = format!("{}.part", path);
+    fs::write(&partial, manifest)?;
+    fs::rename(&partial, path)
+}
+
+/// Reads the crops listed in a manifest written by `write_manifest`.
+pub fn read_manifest(path: &str) -> io::Result<Vec<Crop>> {
+    let contents = fs::read_to_string(path)?;
+    parse_manifest(&contents).map_err(|err| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: invalid manifest: {}", path, err),
+        )
+    })
+}
+
+fn crop_to_json(crop: &Crop) -> String {
+    format!(
+        "  {{\"source\": {}, \"requested\": \"{}x{}\", \"format\": \"{}\", \"path\": {}, \"width\": {}, \"height\": {}, \"hash\": {}, \"data_uri\": {}}}",
+        json_string(&crop.source),
+        crop.requested.0,
+        crop.requested.1,
+        crop.format.extension(),
+        json_string(&crop.path),
+        crop.width,
+        crop.height,
+        crop.hash
+            .as_deref()
+            .map(json_string)
+            .unwrap_or_else(|| "null".to_string()),
+        crop.data_uri
+            .as_deref()
+            .map(json_string)
+            .unwrap_or_else(|| "null".to_string())
+    )
+}
+
+/// A value in a manifest entry. Manifests only hold flat objects of strings,
+/// unsigned integers and nulls, so that is all the parser understands.
+#[derive(Debug)]
+enum Value {
+    Str(String),
+    Num(u32),
+    Null,
+}
+
+fn parse_manifest(contents: &str) -> Result<Vec<Crop>, String> {
+    let mut chars = contents.chars().peekable();
+    let mut crops = Vec::new();
+
+    expect(&mut chars, '[')?;
+    if skip_whitespace(&mut chars) == Some(']') {
+        return Ok(crops);
+    }
+    loop {
+        crops.push(entry_to_crop(parse_object(&mut chars)?)?);
+        match skip_whitespace(&mut chars) {
+            Some(',') => {
+                chars.next();
+            }
+            Some(']') => return Ok(crops),
+            c => return Err(format!("expected ',' or ']', found {:?}", c)),
+        }
+    }
+}
+
+fn entry_to_crop(mut entry: HashMap<String, Value>) -> Result<Crop, String> {
+    let mut take_str = |key: &str| match entry.remove(key) {
+        Some(Value::Str(s)) => Ok(Some(s)),
+        Some(Value::Null) | None => Ok(None),
+        Some(v) => Err(format!("'{}' should be a string, not {:?}", key, v)),
+    };
+    let source = take_str("source")?.ok_or("entry without 'source'")?;
+    let path = take_str("path")?.ok_or("entry without 'path'")?;
+    let hash = take_str("hash")?;
+    let data_uri = take_str("data_uri")?;
+    let requested = take_str("requested")?.ok_or("entry without 'requested'")?;
+    let requested = str_to_size(&requested)?;
+    // Manifests from before extra formats only listed JPEGs.
+    let format = match take_str("format")? {
+        Some(format) => str_to_output_format(&format)?,
+        None => OutputFormat::Jpeg,
+    };
+
+    let mut take_num = |key: &str| match entry.remove(key) {
+        Some(Value::Num(n)) => Ok(n),
+        v => Err(format!("'{}' should be a number, not {:?}", key, v)),
+    };
+    Ok(Crop {
+        source,
+        requested,
+        format,
+        path,
+        width: take_num("width")?,
+        height: take_num("height")?,
+        hash,
+        data_uri,
+    })
+}
+
+fn parse_object(chars: &mut Peekable<Chars>) -> Result<HashMap<String, Value>, String> {
+    let mut entry = HashMap::new();
+    expect(chars, '{')?;
+    if skip_whitespace(chars) == Some('}') {
+        chars.next();
+        return Ok(entry);
+    }
+    loop {
+        skip_whitespace(chars);
+        let key = parse_string(chars)?;
+        expect(chars, ':')?;
+        let value = match skip_whitespace(chars) {
+            Some('"') => Value::Str(parse_string(chars)?),
+            Some('n') => {
+                for c in "null".chars() {
+                    expect(chars, c)?;
+                }
+                Value::Null
+            }
+            Some(c) if c.is_ascii_digit() => {
+                let mut digits = String::new();
+                while let Some(c) = chars.peek().filter(|c| c.is_ascii_digit()) {
+                    digits.push(*c);
+                    chars.next();
+                }
+                Value::Num(digits.parse().map_err(|err| format!("{}", err))?)
+            }
+            c => return Err(format!("unexpected {:?} in value", c)),
+        };
+        entry.insert(key, value);
+        match skip_whitespace(chars) {
+            Some(',') => {
+                chars.next();
+            }
+            Some('}') => {
+                chars.next();
+                return Ok(entry);
+            }
+            c => return Err(format!("expected ',' or '}}', found {:?}", c)),
+        }
+    }
+}
+
+fn parse_string(chars: &mut Peekable<Chars>) -> Result<String, String> {
+    expect(chars, '"')?;
+    let mut s = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(s),
+            Some('\\') => match chars.next() {
+                Some('n') => s.push('\n'),
+                Some('r') => s.push('\r'),
+                Some('t') => s.push('\t'),
+                Some('u') => {
+                    let hex: String = chars.by_ref().take(4).collect();
+                    let c = u32::from_str_radix(&hex, 16)
+                        .ok()
+                        .and_then(char::from_u32)
+                        .ok_or_else(|| format!("invalid escape \\u{}", hex))?;
+                    s.push(c);
+                }
+                Some(c) => s.push(c),
+                None => return Err("unterminated string".to_string()),
+            },
+            Some(c) => s.push(c),
+            None => return Err("unterminated string".to_string()),
+        }
+    }
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) -> Option<char> {
+    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+    chars.peek().copied()
+}
+
+fn expect(chars: &mut Peekable<Chars>, expected: char) -> Result<(), String> {
+    match (skip_whitespace(chars), chars.next()) {
+        (_, Some(c)) if c == expected => Ok(()),
+        (_, c) => Err(format!("expected '{}', found {:?}", expected, c)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cutter::imageprocessing::Size;
+
+    #[test]
+    fn manifests_round_trip() {
+        let crops = vec![
+            Crop {
+                source: "in/\"quoted\" \\ back\tslash\u{1}\n.jpg".to_owned(),
+                requested: Size(200, 100),
+                format: OutputFormat::Jpeg,
+                path: "out/tr\u{e6}kk \u{1f4f7}_200x100px_200w.jpg".to_owned(),
+                width: 200,
+                height: 100,
+                hash: Some("0a1b2c3d".to_owned()),
+                data_uri: None,
+            },
+            Crop {
+                source: "in/small.png".to_owned(),
+                requested: Size(20, 20),
+                format: OutputFormat::Png,
+                path: "out/small_20x20px_20w.png".to_owned(),
+                width: 20,
+                height: 16,
+                hash: None,
+                data_uri: Some("data:image/png;base64,iVBORw0KGgo=".to_owned()),
+            },
+        ];
+        let path =
+            std::env::temp_dir().join(format!("cutter-test-manifest-{}.json", std::process::id()));
+        let path = path.to_string_lossy();
+
+        write_manifest(&path, &crops).unwrap();
+        let read = read_manifest(&path).unwrap();
+        fs::remove_file(path.as_ref()).unwrap();
+
+        assert_eq!(format!("{:?}", read), format!("{:?}", crops));
+    }
+}
@@ -0,0 +1,40 @@
+//! Removing EXIF, XMP and other metadata from encoded JPEGs.
+//!
+//! The `image` crate doesn't copy metadata when re-encoding, so crops start
+//! out clean. Stripping again after encoding guarantees nothing like GPS
+//! coordinates or camera serials reaches published output, whatever the
+//! encoder does.
+
+use super::icc::jpeg_segments;
+
+const MARKER_APP1: u8 = 0xE1;
+const MARKER_APP13: u8 = 0xED;
+const MARKER_COM: u8 = 0xFE;
+
+/// Returns `jpeg` without its `APP1` (EXIF and XMP), `APP13` (IPTC) and
+/// comment segments. Anything that isn't a JPEG is returned unchanged.
+pub fn strip_jpeg_metadata(jpeg: &[u8]) -> Vec<u8> {
+    if !has_jpeg_metadata(jpeg) {
+        return jpeg.to_vec();
+    }
+
+    let mut out = Vec::with_capacity(jpeg.len());
+    let mut header_end = 2;
+    for (marker, payload) in jpeg_segments(jpeg) {
+        let segment = &jpeg[header_end..header_end + 4 + payload.len()];
+        header_end += segment.len();
+        if !matches!(marker, MARKER_APP1 | MARKER_APP13 | MARKER_COM) {
+            out.extend_from_slice(segment);
+        }
+    }
+
+    let mut stripped = jpeg[..2].to_vec();
+    stripped.append(&mut out);
+    stripped.extend_from_slice(&jpeg[header_end..]);
+    stripped
+}
+
+/// Whether `jpeg` carries any of the segments `strip_jpeg_metadata` removes.
+pub fn has_jpeg_metadata(jpeg: &[u8]) -> bool {
+    jpeg_segments(jpeg).any(|(marker, _)| matches!(marker, MARKER_APP1 | MARKER_APP13 | MARKER_COM))
+}
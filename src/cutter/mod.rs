@@ -1,4 +1,20 @@
+pub mod analyze;
+pub mod archive;
+pub mod cache;
+pub mod completions;
+pub mod density;
+pub mod icc;
 pub mod imageprocessing;
 pub mod lib;
+pub mod log;
+pub mod manifest;
+pub mod metadata;
+pub mod preset;
+pub mod progress;
 pub mod s3;
+pub mod selftest;
+pub mod sidecar;
+pub mod signal;
+pub mod store;
+pub mod throttle;
 pub mod util;
@@ -0,0 +1,47 @@
+use super::imageprocessing::{ResizeMode, Size};
+
+/// A named set of crop sizes for a common use.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Preset {
+    /// Responsive images: fit within 320 to 1920 pixels, keeping the
+    /// source's aspect ratio.
+    Web,
+    /// Link preview and social media sizes, cropped to fill.
+    Social,
+}
+
+impl Preset {
+    pub fn crop_sizes(&self) -> Vec<Size> {
+        match self {
+            Preset::Web => vec![
+                Size(320, 320),
+                Size(640, 640),
+                Size(1280, 1280),
+                Size(1920, 1920),
+            ],
+            Preset::Social => vec![
+                // og:image and Twitter's large summary card
+                Size(1200, 630),
+                Size(1200, 600),
+                // Square and portrait feed posts
+                Size(1080, 1080),
+                Size(1080, 1350),
+            ],
+        }
+    }
+
+    pub fn resize_mode(&self) -> ResizeMode {
+        match self {
+            Preset::Web => ResizeMode::Fit,
+            Preset::Social => ResizeMode::Fill,
+        }
+    }
+}
+
+pub fn str_to_preset(s: &str) -> Result<Preset, String> {
+    match s {
+        "web" => Ok(Preset::Web),
+        "social" => Ok(Preset::Social),
+        _ => Err(format!("unknown preset '{}', expected web or social", s)),
+    }
+}
@@ -0,0 +1,129 @@
+use std::fs::File;
+use std::io::Write;
+use std::num::NonZeroU32;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::log;
+use super::util::print_list_iter_status;
+
+/// Weight of the latest operation in the moving average of operation times.
+const SMOOTHING: f64 = 0.2;
+
+/// Reports progress of a batch of operations, as log lines for humans and
+/// optionally as JSON lines (`{"done":N,"total":M}`) to a file descriptor
+/// for wrapping tools.
+pub struct Progress {
+    verbose: bool,
+    every: Option<NonZeroU32>,
+    /// Dropped after the first failed write, so a closed pipe is only
+    /// reported once.
+    sink: Mutex<Option<File>>,
+    timing: Mutex<Timing>,
+}
+
+/// When the last operation finished, and the moving average of the time
+/// between finished operations.
+struct Timing {
+    last: Instant,
+    average: Option<f64>,
+}
+
+impl Timing {
+    /// Folds the operation finished since the last call into the average,
+    /// and estimates how long the `remaining` ones will take.
+    fn estimate(&mut self, remaining: u32) -> Option<Duration> {
+        let now = Instant::now();
+        let elapsed = (now - self.last).as_secs_f64();
+        self.average = Some(match self.average {
+            Some(average) => average + SMOOTHING * (elapsed - average),
+            None => elapsed,
+        });
+        self.last = now;
+        self.average
+            .map(|average| Duration::from_secs_f64(average * remaining as f64))
+    }
+}
+
+impl Progress {
+    pub fn new(verbose: bool, every: Option<NonZeroU32>, progress_fd: Option<i32>) -> Progress {
+        Progress {
+            verbose,
+            every,
+            sink: Mutex::new(progress_fd.and_then(open_fd)),
+            timing: Mutex::new(Timing {
+                last: Instant::now(),
+                average: None,
+            }),
+        }
+    }
+
+    /// Records that `done` of `total` operations have finished, `done` being
+    /// one more than at the previous call.
+    pub fn update(&self, done: u32, total: u32, prefix: &str) {
+        let eta = self
+            .timing
+            .lock()
+            .unwrap()
+            .estimate(total.saturating_sub(done));
+        print_list_iter_status(done, total, prefix, self.verbose, self.every, eta);
+
+        let mut sink = self.sink.lock().unwrap();
+        if let Some(file) = sink.as_mut() {
+            if let Err(err) = writeln!(file, "{{\"done\":{},\"total\":{}}}", done, total) {
+                log::warn(&format!(
+                    "failed to write progress, no longer writing it: {}",
+                    err
+                ));
+                *sink = None;
+            }
+        }
+    }
+}
+
+/// Parses a `--progress-fd`, which must already be open for writing.
+#[cfg(unix)]
+pub fn str_to_progress_fd(s: &str) -> Result<i32, String> {
+    let fd: i32 = s
+        .parse()
+        .map_err(|_| format!("not a file descriptor: '{}'", s))?;
+    if fd == libc::STDIN_FILENO {
+        return Err("file descriptor 0 is standard input".to_owned());
+    }
+    // Safety: F_GETFL only reads the descriptor's flags.
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 {
+        return Err(format!("file descriptor {} is not open", fd));
+    }
+    if flags & libc::O_ACCMODE == libc::O_RDONLY {
+        return Err(format!("file descriptor {} is not open for writing", fd));
+    }
+    Ok(fd)
+}
+
+#[cfg(not(unix))]
+pub fn str_to_progress_fd(_s: &str) -> Result<i32, String> {
+    Err("--progress-fd is only supported on unix".to_owned())
+}
+
+/// Opens a duplicate of `fd`, so the caller's descriptor stays open when
+/// the progress sink is dropped.
+#[cfg(unix)]
+fn open_fd(fd: i32) -> Option<File> {
+    use std::os::unix::io::BorrowedFd;
+
+    // Safety: `fd` was checked to be open by `str_to_progress_fd`, and is
+    // only borrowed for the duration of the call.
+    match unsafe { BorrowedFd::borrow_raw(fd) }.try_clone_to_owned() {
+        Ok(owned) => Some(File::from(owned)),
+        Err(err) => {
+            log::warn(&format!("cannot write progress to fd {}: {}", fd, err));
+            None
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn open_fd(_fd: i32) -> Option<File> {
+    None
+}
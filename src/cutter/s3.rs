@@ -1,146 +1,1114 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fs;
-use std::fs::File;
-use std::io::Write;
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::num::NonZeroUsize;
 use std::path::Path;
+use std::process;
 use std::str;
+use std::str::FromStr;
+use std::time::UNIX_EPOCH;
 
-use super::util::print_list_iter_status;
-
-pub async fn download_from_s3(
-    bucket: &str,
-    _region: &str,
-    prefix: &str,
-    local_path: &str,
-    overwrite: bool,
-    clean: bool,
-    verbose: bool,
-) {
-    println!(
+use aws_sdk_s3::model::ServerSideEncryption;
+use aws_sdk_s3::types::SdkError;
+use aws_sdk_s3::{Credentials, Endpoint, Region};
+use futures_util::{stream, StreamExt};
+use regex::Regex;
+
+use super::imageprocessing::Failure;
+use super::log;
+use super::signal::interrupted;
+use super::store::{open_store, ObjectInfo, ObjectStore};
+use super::throttle::Throttle;
+use super::util::{print_list_iter_status, remove_dir_guarded};
+use crate::{Config, DEFAULT_REGION};
+
+/// Most tags S3 allows on a single object.
+pub const MAX_OBJECT_TAGS: usize = 10;
+
+/// Endpoint of Google Cloud Storage's S3-compatible XML API.
+const GCS_ENDPOINT: &str = "https://storage.googleapis.com";
+
+/// A KMS key, multi-region key (`mrk-...`) or alias, bare or as an ARN in
+/// any partition.
+const KMS_KEY_ID_PATTERN: &str = concat!(
+    r"^((arn:aws[a-z-]*:kms:[a-z0-9-]+:\d{12}:key/)?([0-9a-fA-F-]{36}|mrk-[0-9a-f]{32})",
+    r"|(arn:aws[a-z-]*:kms:[a-z0-9-]+:\d{12}:)?alias/[a-zA-Z0-9/_-]{1,250})$"
+);
+
+/// Suffix of files that are still being downloaded.
+pub const PARTIAL_DOWNLOAD_SUFFIX: &str = ".part";
+
+/// Matches object keys of crops generated by cutter, e.g.
+/// `beach_200x200px_200w.jpg`, `beach_200x200px_200w-1.jpg` or `beach_thumb.jpg`.
+pub const DEFAULT_CROP_KEY_PATTERN: &str =
+    r"_(\d+x\d+(px_\d+w)?|thumb)(\.[0-9a-f]+)?(-\d+)?\.[A-Za-z0-9]+$";
+
+/// Downloads the source images in the bucket. Objects that fail are returned
+/// when `--continue-on-s3-error` is set; otherwise the first failure aborts.
+pub async fn download_from_s3(config: &Config) -> Vec<Failure> {
+    let bucket = config
+        .s3_bucket_name
+        .as_deref()
+        .expect("need s3 bucket name to fetch from remote");
+    let local_path = config.source_dir();
+    let verbose = config.verbose;
+
+    log::info(&format!(
         "Downloading files from S3 bucket '{}' ({})...",
-        bucket, prefix
-    );
-    let config = aws_config::load_from_env().await;
-    let client = aws_sdk_s3::Client::new(&config);
+        bucket,
+        config.s3_prefixes.join(", ")
+    ));
+    let store = open_store(config, bucket, config.download_concurrency).await;
 
-    let resp = client
-        .list_objects_v2()
-        .bucket(bucket)
-        .send()
-        .await
-        .expect("failed to send s3 request");
-    let bucket_contents = resp.contents().unwrap_or_default();
+    // With several prefixes each is listed on its own and downloaded into a
+    // directory of the same name, so galleries don't collide.
+    let listings: Vec<Option<&str>> = if config.namespaced_prefixes() {
+        config
+            .s3_prefixes
+            .iter()
+            .map(|p| Some(p.as_str()))
+            .collect()
+    } else {
+        vec![None]
+    };
+
+    // Each key with the prefix it was listed under and when it last changed.
+    let mut listed: Vec<(Option<&str>, String, Option<i64>)> = Vec::new();
+    let mut e_tags = HashMap::new();
+    if let Some(keys_file) = &config.s3_keys_file {
+        // The caller already knows the keys, so there's nothing to list.
+        let keys = read_keys_file(keys_file).unwrap_or_else(|err| {
+            log::error(&err);
+            process::exit(1);
+        });
+        for key in keys {
+            let prefix = listings
+                .iter()
+                .flatten()
+                .copied()
+                .find(|prefix| key.starts_with(&format!("{}/", prefix.trim_end_matches('/'))));
+            listed.push((prefix, key, None));
+        }
+    } else {
+        for prefix in listings {
+            let list_prefix = prefix.map(|prefix| format!("{}/", prefix.trim_end_matches('/')));
+            let objects = store
+                .list(list_prefix.as_deref())
+                .await
+                .unwrap_or_else(|err| {
+                    log::error(&err);
+                    process::exit(1);
+                });
+            for obj in objects {
+                if let Some(e_tag) = obj.e_tag {
+                    e_tags.insert(obj.key.to_owned(), e_tag);
+                }
+                listed.push((prefix, obj.key, obj.last_modified));
+            }
+        }
+    }
 
     let mut all_files = Vec::new();
+    let mut local_paths = HashMap::new();
+    let mut last_modified = HashMap::new();
+    let mut unsafe_keys = 0;
 
-    for obj in bucket_contents {
-        all_files.push(obj.key().expect("failed to get object key"));
+    for (prefix, key, modified) in listed {
+        if !is_safe_key(&key) {
+            log::warn(&format!(
+                "skipping {}: key points outside the download directory",
+                key
+            ));
+            unsafe_keys += 1;
+            continue;
+        }
+        if let Some(modified) = modified {
+            last_modified.insert(key.to_owned(), modified);
+        }
+        let path = match (&config.local_root, prefix) {
+            // An explicit root mirrors the bucket's keys exactly.
+            (Some(root), _) => format!("{}/{}", root.trim_end_matches('/'), key),
+            (None, Some(prefix)) => namespaced_file_path(local_path, prefix, &key),
+            (None, None) => local_file_path(local_path, &key),
+        };
+        local_paths.insert(key.to_owned(), path);
+        all_files.push(key);
     }
 
     let mut files = Vec::new();
 
-    let mut skipped = 0;
+    let mut skipped = unsafe_keys;
+    let mut up_to_date = 0;
 
+    let crop_stems = crop_stems(&all_files, &config.crop_key_pattern);
     for file in &all_files {
-        if file.contains("_200")
-            || file.contains("_400")
-            || file.contains("_800")
-            || file.contains("_1920")
-            || file.contains("_thumb")
-        {
+        if !should_download(file, &crop_stems, config) {
             skipped += 1;
-            continue;
-        }
-
-        let _thumb_key = &file.replace(".jpg", "_thumb.jpg");
-
-        let valid_file_name = !file.is_empty() && file != &format!("{}/", prefix);
-        let has_sizes = file.contains('_');
-
-        if valid_file_name && overwrite || !has_sizes {
-            files.push(file);
+        } else if !config.overwrite && is_up_to_date(&local_paths[file], last_modified.get(file)) {
+            up_to_date += 1;
         } else {
-            skipped += 1;
+            files.push(file);
         }
     }
 
     let root_dir = local_path;
 
-    println!(
-        "Downloading {} files to {} (skipped {})",
+    log::info(&format!(
+        "Downloading {} files to {} (skipped {}, {} up to date)",
         files.len(),
         &root_dir,
-        skipped
-    );
+        skipped,
+        up_to_date
+    ));
     let numfiles = files.len();
-    let mut counter = 1;
 
-    if Path::new(&root_dir).exists() && (clean || overwrite) {
-        println!("Removing existing directory...");
-        fs::remove_dir_all(&root_dir).unwrap();
+    if Path::new(root_dir).exists() && (config.clean || config.overwrite) {
+        log::warn("Removing existing directory...");
+        if let Err(err) = remove_dir_guarded(root_dir, config.assume_yes) {
+            log::error(&err);
+            process::exit(1);
+        }
+    }
+    if let Err(err) = fs::create_dir_all(root_dir) {
+        log::error(&format!("{}: {}", root_dir, err));
+        process::exit(1);
+    }
+
+    let (store, local_paths, e_tags) = (&store, &local_paths, &e_tags);
+    let mut downloads = stream::iter(&files)
+        .map(|file| async move {
+            // After Ctrl-C, downloads already started finish but no new ones do.
+            if interrupted() {
+                return (file, None);
+            }
+            let result = download_object(
+                store,
+                file,
+                &local_paths[*file],
+                e_tags.get(*file).map(String::as_str),
+                config.resume_downloads,
+            )
+            .await;
+            (file, Some(result))
+        })
+        .buffer_unordered(config.download_concurrency.get());
+    let mut failures = Vec::new();
+    let mut counter = 0;
+    let mut skipped = 0;
+    while let Some((file, result)) = downloads.next().await {
+        let result = match result {
+            Some(result) => result,
+            None => {
+                skipped += 1;
+                continue;
+            }
+        };
+        counter += 1;
+        print_list_iter_status(
+            counter,
+            numfiles as u32,
+            "Downloaded",
+            verbose,
+            config.progress_every,
+            None,
+        );
+        if let Err(reason) = result {
+            failures.push(object_failure(config, file, reason));
+        }
+    }
+    if skipped > 0 {
+        log::warn(&format!(
+            "Interrupted, {} of {} files were not downloaded",
+            skipped, numfiles
+        ));
+    }
+    failures
+}
+
+async fn download_object(
+    store: &impl ObjectStore,
+    key: &str,
+    path: &str,
+    e_tag: Option<&str>,
+    resume: bool,
+) -> Result<(), String> {
+    if let Some(parent) = Path::new(path).parent() {
+        fs::create_dir_all(parent).map_err(|err| err.to_string())?;
     }
-    fs::create_dir_all(&root_dir).unwrap();
 
-    for file in &files {
-        let gallery_image: Vec<&str> = file.split('/').collect();
-        let mut path = format!("{}/{}", local_path, &file);
-        if gallery_image.len() > 1 {
-            path = format!("{}/{}", local_path, &gallery_image[1]);
+    // Write to a partial file first so an interrupted download never
+    // leaves a truncated file behind under the real name.
+    let part_path = format!("{}{}", path, PARTIAL_DOWNLOAD_SUFFIX);
+    // The ETag the partial file was started from, so a newer version of the
+    // object isn't appended to the start of an older one.
+    let e_tag_path = format!("{}.etag{}", path, PARTIAL_DOWNLOAD_SUFFIX);
+    let started_from = fs::read_to_string(&e_tag_path).ok();
+    let offset = match fs::metadata(&part_path) {
+        Ok(meta) if resume && started_from.as_deref() == e_tag => meta.len(),
+        _ => 0,
+    };
+
+    if let Some(e_tag) = e_tag {
+        fs::write(&e_tag_path, e_tag).map_err(|err| format!("{}: {}", e_tag_path, err))?;
+    }
+    store.get(key, &part_path, offset, e_tag).await?;
+    fs::rename(&part_path, path).map_err(|err| format!("{}: {}", path, err))?;
+    let _ = fs::remove_file(&e_tag_path);
+    Ok(())
+}
+
+/// Service the bucket is in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Storage {
+    S3,
+    /// Google Cloud Storage, through its S3-compatible XML API.
+    Gcs,
+    /// A local directory, named by the bucket name.
+    Local,
+}
+
+pub fn str_to_storage(s: &str) -> Result<Storage, String> {
+    match s {
+        "s3" => Ok(Storage::S3),
+        "gcs" => Ok(Storage::Gcs),
+        "local" => Ok(Storage::Local),
+        _ => Err(format!("unknown storage '{}'", s)),
+    }
+}
+
+/// A bucket in S3, or in Google Cloud Storage through its XML API.
+pub struct S3Store {
+    client: aws_sdk_s3::Client,
+    bucket: String,
+    /// URL-encoded `--object-tag`s, set on every uploaded object.
+    tagging: Option<String>,
+    /// `--sse-kms-key-id`, encrypting every uploaded object with that key.
+    sse_kms_key_id: Option<String>,
+    throttle: Throttle,
+}
+
+impl S3Store {
+    /// Connects to `bucket`, sending at most `concurrency` requests at once.
+    pub async fn connect(config: &Config, bucket: &str, concurrency: NonZeroUsize) -> Self {
+        S3Store {
+            client: client_for_bucket(config, bucket).await,
+            bucket: bucket.to_owned(),
+            tagging: if config.object_tags.is_empty() {
+                None
+            } else {
+                Some(tagging(&config.object_tags))
+            },
+            sse_kms_key_id: config.sse_kms_key_id.to_owned(),
+            throttle: Throttle::new(concurrency),
         }
-        print_list_iter_status(counter, numfiles as u32, "Downloaded", verbose);
+    }
+}
 
-        let resp = client
-            .get_object()
-            .bucket(bucket)
-            .key(file.to_string())
-            .send()
+impl ObjectStore for S3Store {
+    async fn list(&self, prefix: Option<&str>) -> Result<Vec<ObjectInfo>, String> {
+        let mut objects = Vec::new();
+        let mut continuation_token = None;
+        loop {
+            let resp = self
+                .throttle
+                .send(|| {
+                    self.client
+                        .list_objects_v2()
+                        .bucket(&self.bucket)
+                        .set_prefix(prefix.map(str::to_owned))
+                        .set_continuation_token(continuation_token.to_owned())
+                        .send()
+                })
+                .await
+                .map_err(|err| format!("failed to list objects: {}", err))?;
+            for obj in resp.contents().unwrap_or_default() {
+                if let Some(key) = obj.key() {
+                    objects.push(ObjectInfo {
+                        key: key.to_owned(),
+                        size: obj.size(),
+                        e_tag: obj.e_tag().map(str::to_owned),
+                        last_modified: obj.last_modified().map(|modified| modified.secs()),
+                    });
+                }
+            }
+            continuation_token = resp.next_continuation_token().map(str::to_owned);
+            if !resp.is_truncated() || continuation_token.is_none() {
+                return Ok(objects);
+            }
+        }
+    }
+
+    async fn get(
+        &self,
+        key: &str,
+        path: &str,
+        offset: u64,
+        if_match: Option<&str>,
+    ) -> Result<(), String> {
+        let request = |offset: u64| {
+            self.throttle.send(move || {
+                let mut request = self.client.get_object().bucket(&self.bucket).key(key);
+                if offset > 0 {
+                    request = request.range(format!("bytes={}-", offset));
+                    if let Some(e_tag) = if_match {
+                        request = request.if_match(e_tag);
+                    }
+                }
+                request.send()
+            })
+        };
+        let resp = match request(offset).await {
+            // The object changed since the partial file was started.
+            Err(SdkError::ServiceError { raw, .. })
+                if offset > 0 && raw.http().status().as_u16() == 412 =>
+            {
+                request(0).await
+            }
+            resp => resp,
+        }
+        .map_err(|err| format!("failed to download file: {}", err))?;
+        // Only append if the server actually honoured the range request.
+        let append = offset > 0 && resp.content_range().is_some();
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .append(append)
+            .truncate(!append)
+            .open(path)
+            .map_err(|err| format!("{}: {}", path, err))?;
+        // Write the body as it arrives, so memory use doesn't grow with the object.
+        let mut body = resp.body;
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.map_err(|err| format!("failed to read data: {}", err))?;
+            file.write_all(&chunk)
+                .map_err(|err| format!("{}: {}", path, err))?;
+        }
+        Ok(())
+    }
+
+    async fn put(&self, key: &str, body: Vec<u8>, content_type: &str) -> Result<(), String> {
+        self.throttle
+            .send(|| {
+                self.client
+                    .put_object()
+                    .bucket(&self.bucket)
+                    .key(key)
+                    .content_type(content_type)
+                    .set_tagging(self.tagging.to_owned())
+                    .set_server_side_encryption(
+                        self.sse_kms_key_id
+                            .as_ref()
+                            .map(|_| ServerSideEncryption::AwsKms),
+                    )
+                    .set_ssekms_key_id(self.sse_kms_key_id.to_owned())
+                    .body(body.clone().into())
+                    .send()
+            })
             .await
-            .expect("failed to download file");
-        let data = resp.body.collect().await.expect("failed to collect data");
-        let mut buffer = File::create(path).unwrap();
-        buffer.write_all(&data.into_bytes()).unwrap();
-        counter += 1;
+            .map_err(|err| format!("failed to upload: {}", err))?;
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), String> {
+        self.throttle
+            .send(|| {
+                self.client
+                    .delete_object()
+                    .bucket(&self.bucket)
+                    .key(key)
+                    .send()
+            })
+            .await
+            .map_err(|err| format!("failed to delete: {}", err))?;
+        Ok(())
+    }
+}
+
+/// A credential given on the command line, kept out of logged output.
+#[derive(Clone)]
+pub struct Secret(pub String);
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(***)")
     }
 }
 
-pub async fn upload_to_s3(
-    bucket: &str,
-    _region: &str,
-    prefix: &str,
-    _tmp_dir: &str,
-    files: Vec<String>,
-    verbose: bool,
-) {
-    let config = aws_config::load_from_env().await;
-    let client = aws_sdk_s3::Client::new(&config);
+impl FromStr for Secret {
+    type Err = String;
 
-    println!("Uploading {} files to S3 bucket '{}'", files.len(), bucket,);
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Secret(s.to_owned()))
+    }
+}
+
+/// Credentials given with `--aws-access-key-id` and friends, if any.
+fn explicit_credentials(config: &Config) -> Option<Credentials> {
+    match (&config.aws_access_key_id, &config.aws_secret_access_key) {
+        (Some(access_key_id), Some(secret_access_key)) => Some(Credentials::new(
+            access_key_id,
+            &secret_access_key.0,
+            config
+                .aws_session_token
+                .as_ref()
+                .map(|token| token.0.to_owned()),
+            None,
+            "cutter",
+        )),
+        _ => None,
+    }
+}
+
+/// Creates an S3 client for `bucket`, in the region given by `--s3-region`,
+/// the region map or the environment, in that order. Credentials given on the
+/// command line take precedence over the environment's. For GCS these are an
+/// HMAC key.
+async fn client_for_bucket(config: &Config, bucket: &str) -> aws_sdk_s3::Client {
+    let loader = |region: Option<String>| {
+        let mut loader = aws_config::from_env();
+        if let Some(region) = region {
+            loader = loader.region(Region::new(region));
+        }
+        if let Some(credentials) = explicit_credentials(config) {
+            loader = loader.credentials_provider(credentials);
+        }
+        loader
+    };
+    let mut sdk_config = loader(config.region_for(bucket)).load().await;
+    if sdk_config.region().is_none() {
+        sdk_config = loader(Some(DEFAULT_REGION.to_owned())).load().await;
+    }
+    let mut s3_config = aws_sdk_s3::config::Builder::from(&sdk_config);
+    if config.storage == Storage::Gcs {
+        s3_config = s3_config.endpoint_resolver(Endpoint::immutable(
+            GCS_ENDPOINT.parse().expect("valid GCS endpoint"),
+        ));
+    }
+    aws_sdk_s3::Client::from_conf(s3_config.build())
+}
+
+/// Parses an object tag given as `key=value`, checking it against the
+/// limits S3 puts on tags.
+pub fn str_to_tag(s: &str) -> Result<(String, String), String> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("tag must be key=value, not '{}'", s))?;
+    let allowed = |c: char| c.is_alphanumeric() || " +-=._:/@".contains(c);
+    if key.is_empty() || key.chars().count() > 128 {
+        return Err(format!("tag key '{}' must be 1 to 128 characters", key));
+    }
+    if value.chars().count() > 256 {
+        return Err(format!("value of tag '{}' is over 256 characters", key));
+    }
+    if key.starts_with("aws:") {
+        return Err(format!("tag key '{}' uses the reserved aws: prefix", key));
+    }
+    if !key.chars().chain(value.chars()).all(allowed) {
+        return Err(format!(
+            "tag '{}' may only contain letters, digits, spaces and + - = . _ : / @",
+            s
+        ));
+    }
+    Ok((key.to_owned(), value.to_owned()))
+}
+
+/// Checks a KMS key given to `--sse-kms-key-id`: a key ID, a key or alias
+/// ARN, or an alias name.
+pub fn str_to_kms_key_id(s: &str) -> Result<String, String> {
+    if Regex::new(KMS_KEY_ID_PATTERN).unwrap().is_match(s) {
+        Ok(s.to_owned())
+    } else {
+        Err(format!(
+            "'{}' is not a KMS key ID, key ARN or alias (arn:aws:kms:<region>:<account>:key/<id>)",
+            s
+        ))
+    }
+}
+
+/// Object tags as the URL query string `put_object` expects.
+fn tagging(tags: &[(String, String)]) -> String {
+    let encode = |s: &str| {
+        s.bytes()
+            .map(|b| match b {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                    (b as char).to_string()
+                }
+                _ => format!("%{:02X}", b),
+            })
+            .collect::<String>()
+    };
+    tags.iter()
+        .map(|(key, value)| format!("{}={}", encode(key), encode(value)))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Reads a bucket to region map from a file of `bucket = region` lines.
+/// Blank lines and lines starting with `#` are ignored.
+pub fn read_region_map(path: &str) -> Result<HashMap<String, String>, String> {
+    let contents = fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+    let mut region_map = HashMap::new();
+    for (lineno, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (bucket, region) = line
+            .split_once('=')
+            .ok_or_else(|| format!("{}:{}: expected 'bucket = region'", path, lineno + 1))?;
+        region_map.insert(bucket.trim().to_owned(), region.trim().to_owned());
+    }
+    Ok(region_map)
+}
+
+/// Reads the object keys to download from a file of one key per line.
+/// Blank lines and lines starting with `#` are ignored.
+pub fn read_keys_file(path: &str) -> Result<Vec<String>, String> {
+    let contents = fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_owned)
+        .collect())
+}
+
+/// Whether `key` stays inside the directory it is downloaded to: no `..`
+/// components and not absolute.
+fn is_safe_key(key: &str) -> bool {
+    !key.starts_with('/') && !key.contains('\\') && key.split('/').all(|part| part != "..")
+}
+
+/// Local path an object is downloaded to.
+fn local_file_path(local_path: &str, key: &str) -> String {
+    let gallery_image: Vec<&str> = key.split('/').collect();
+    if gallery_image.len() > 1 {
+        format!("{}/{}", local_path, &gallery_image[1])
+    } else {
+        format!("{}/{}", local_path, key)
+    }
+}
+
+/// Local path an object listed under `prefix` is downloaded to, keeping
+/// the prefix as a directory.
+fn namespaced_file_path(local_path: &str, prefix: &str, key: &str) -> String {
+    let prefix = prefix.trim_end_matches('/');
+    let name = key
+        .strip_prefix(prefix)
+        .unwrap_or(key)
+        .trim_start_matches('/');
+    format!("{}/{}/{}", local_path, prefix, name)
+}
+
+/// Whether the local file at `path` is at least as new as the remote object
+/// last modified at `remote_secs` (seconds since the epoch).
+fn is_up_to_date(path: &str, remote_secs: Option<&i64>) -> bool {
+    let local_secs = fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|since_epoch| since_epoch.as_secs() as i64);
+    match (local_secs, remote_secs) {
+        (Some(local), Some(remote)) => local >= *remote,
+        _ => false,
+    }
+}
+
+/// Whether the object `key` is a source image that should be downloaded.
+///
+/// Directory markers and generated crops are never downloaded. Unless
+/// overwriting, sources that already have crops, whose stems are in
+/// `crop_stems`, are skipped.
+pub fn should_download(key: &str, crop_stems: &HashSet<String>, cfg: &Config) -> bool {
+    if key.is_empty() || key.ends_with('/') {
+        return false;
+    }
+    if is_crop_key(key, &cfg.crop_key_pattern) {
+        return false;
+    }
+    let stem = key.rsplit_once('.').map_or(key, |(stem, _)| stem);
+    cfg.overwrite || !crop_stems.contains(stem)
+}
+
+/// Whether `key` names a crop generated by cutter rather than a source image.
+fn is_crop_key(key: &str, crop_key_pattern: &Regex) -> bool {
+    crop_key_pattern.is_match(key)
+}
+
+/// Stems of the sources the crops among `all_keys` were generated from, e.g.
+/// `gallery/beach` for `gallery/beach_200x200px_200w.jpg`.
+pub fn crop_stems(all_keys: &[String], crop_key_pattern: &Regex) -> HashSet<String> {
+    all_keys
+        .iter()
+        .filter_map(|key| {
+            crop_key_pattern
+                .find(key)
+                .map(|crop| key[..crop.start()].to_owned())
+        })
+        .collect()
+}
+
+/// Uploads `files` to the bucket, returning those that failed when
+/// `--continue-on-s3-error` is set.
+pub async fn upload_to_s3(config: &Config, files: Vec<String>) -> Vec<Failure> {
+    let bucket = config
+        .s3_bucket_name
+        .as_deref()
+        .expect("need s3 bucket name to upload to remote");
+    let prefix = upload_prefix(config);
+
+    let store = open_store(config, bucket, config.upload_concurrency).await;
+
+    log::info(&format!(
+        "Uploading {} files to S3 bucket '{}'",
+        files.len(),
+        bucket
+    ));
 
-    let mut counter = 1;
     let numfiles = files.len();
-    for file in &files {
-        print_list_iter_status(counter, numfiles as u32, "Uploaded", verbose);
-        let body = aws_sdk_s3::types::ByteStream::from_path(Path::new(file))
+    let store = &store;
+    let mut uploads = stream::iter(&files)
+        .map(|file| async move {
+            // After Ctrl-C, uploads already started finish but no new ones do.
+            if interrupted() {
+                return (file, None);
+            }
+            let result = upload_object(store, prefix, &config.tmp_dir, file).await;
+            (file, Some(result))
+        })
+        .buffer_unordered(config.upload_concurrency.get());
+    let mut failures = Vec::new();
+    let mut counter = 0;
+    let mut skipped = 0;
+    while let Some((file, result)) = uploads.next().await {
+        let result = match result {
+            Some(result) => result,
+            None => {
+                skipped += 1;
+                continue;
+            }
+        };
+        counter += 1;
+        print_list_iter_status(
+            counter,
+            numfiles as u32,
+            "Uploaded",
+            config.verbose,
+            config.progress_every,
+            None,
+        );
+        if let Err(reason) = result {
+            failures.push(object_failure(config, file, reason));
+        }
+    }
+    if skipped > 0 {
+        log::warn(&format!(
+            "Interrupted, {} of {} files were not uploaded",
+            skipped, numfiles
+        ));
+    }
+    failures
+}
+
+/// Prefix crops are uploaded under.
+fn upload_prefix(config: &Config) -> Option<&str> {
+    // Namespaced output, and output mirroring the keys under --local-root,
+    // already starts with the prefix directory.
+    if let Some(output_prefix) = &config.s3_output_prefix {
+        Some(output_prefix.as_str())
+    } else if config.namespaced_prefixes() || config.local_root.is_some() {
+        None
+    } else {
+        Some(config.s3_prefixes.first().map_or("", String::as_str))
+    }
+}
+
+/// Key the local crop `file` is uploaded to.
+fn object_key(prefix: Option<&str>, tmp_dir: &str, file: &str) -> String {
+    // @ToDo: Fix output if files are served locally.
+    // They're currently prefixed with the folder name sent in through config
+    // But need the prefix from S3.
+    let file_name = Path::new(file)
+        .strip_prefix(tmp_dir)
+        .unwrap_or_else(|_| Path::new(Path::new(file).file_name().unwrap()))
+        .to_str()
+        .unwrap()
+        .to_owned();
+
+    match prefix {
+        Some(prefix) => format!("{}/{}", prefix, &file_name),
+        None => file_name,
+    }
+}
+
+/// Lists the bucket after an upload and checks that every one of `files` is
+/// there with the local size and, for single-part uploads, the local MD5 as
+/// its ETag. Returns the missing and mismatched objects.
+pub async fn verify_upload(config: &Config, files: &[String]) -> Vec<Failure> {
+    let bucket = config
+        .s3_bucket_name
+        .as_deref()
+        .expect("need s3 bucket name to verify uploads");
+    let prefix = upload_prefix(config);
+    let store = open_store(config, bucket, config.upload_concurrency).await;
+
+    log::info(&format!(
+        "Verifying {} objects in S3 bucket '{}'",
+        files.len(),
+        bucket
+    ));
+
+    let list_prefix = prefix
+        .filter(|p| !p.is_empty())
+        .map(|prefix| format!("{}/", prefix));
+    let remote: HashMap<String, (i64, Option<String>)> =
+        match store.list(list_prefix.as_deref()).await {
+            Ok(objects) => objects
+                .into_iter()
+                .map(|obj| (obj.key, (obj.size, obj.e_tag)))
+                .collect(),
+            Err(reason) => {
+                log::error(&reason);
+                return vec![Failure {
+                    source: bucket.to_owned(),
+                    size: None,
+                    reason,
+                }];
+            }
+        };
+
+    let mut failures = Vec::new();
+    for file in files {
+        let key = object_key(prefix, &config.tmp_dir, file);
+        let reason = match (remote.get(&key), fs::read(file)) {
+            (_, Err(err)) => Some(format!("failed to read local file: {}", err)),
+            (None, _) => Some(format!("{} is missing from the bucket", key)),
+            (Some((size, _)), Ok(data)) if *size != data.len() as i64 => Some(format!(
+                "{} is {} bytes in the bucket, {} locally",
+                key,
+                size,
+                data.len()
+            )),
+            // KMS-encrypted objects have ETags that aren't an MD5.
+            (Some((_, Some(etag))), Ok(data)) if config.sse_kms_key_id.is_none() => {
+                let etag = etag.trim_matches('"');
+                // Neither do multipart uploads, whose ETags end in a part count.
+                let digest = format!("{:x}", md5::compute(&data));
+                if etag.len() == digest.len() && !etag.contains('-') && etag != digest {
+                    Some(format!("{} has ETag {}, expected {}", key, etag, digest))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+        if let Some(reason) = reason {
+            log::error(&format!("{}: {}", file, reason));
+            failures.push(Failure {
+                source: file.to_owned(),
+                size: None,
+                reason,
+            });
+        }
+    }
+    log::info(&format!(
+        "Verified {} objects, {} missing or mismatched",
+        files.len(),
+        failures.len()
+    ));
+    failures
+}
+
+/// Records a failed download or upload, or aborts the run unless
+/// `--continue-on-s3-error` is set.
+fn object_failure(config: &Config, object: &str, reason: String) -> Failure {
+    log::error(&format!("{}: {}", object, reason));
+    if !config.continue_on_error {
+        process::exit(1);
+    }
+    Failure {
+        source: object.to_owned(),
+        size: None,
+        reason,
+    }
+}
+
+async fn upload_object(
+    store: &impl ObjectStore,
+    prefix: Option<&str>,
+    tmp_dir: &str,
+    file: &str,
+) -> Result<(), String> {
+    // Read up front, as a throttled request is sent again with the same body.
+    let body = fs::read(file).map_err(|err| format!("failed to read file contents: {}", err))?;
+    let s3_file_path = object_key(prefix, tmp_dir, file);
+    store.put(&s3_file_path, body, content_type(file)).await
+}
+
+/// Content type of the file at `path`, judged by its magic bytes so misnamed
+/// files are still served correctly, falling back to its extension.
+fn content_type(path: &str) -> &'static str {
+    let mut header = Vec::with_capacity(12);
+    if let Ok(file) = fs::File::open(path) {
+        let _ = file.take(12).read_to_end(&mut header);
+    }
+    sniff_content_type(&header).unwrap_or_else(|| {
+        match Path::new(path)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_lowercase())
+            .as_deref()
+        {
+            Some("jpg" | "jpeg") => "image/jpeg",
+            Some("png") => "image/png",
+            Some("webp") => "image/webp",
+            Some("avif") => "image/avif",
+            Some("gif") => "image/gif",
+            _ => "application/octet-stream",
+        }
+    })
+}
+
+fn sniff_content_type(header: &[u8]) -> Option<&'static str> {
+    match header {
+        [0xff, 0xd8, 0xff, ..] => Some("image/jpeg"),
+        [0x89, b'P', b'N', b'G', ..] => Some("image/png"),
+        [b'G', b'I', b'F', b'8', ..] => Some("image/gif"),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some("image/webp"),
+        [_, _, _, _, b'f', b't', b'y', b'p', b'a', b'v', b'i', b'f' | b's', ..] => {
+            Some("image/avif")
+        }
+        _ => None,
+    }
+}
+
+/// Round-trips a small probe object through the configured bucket to check
+/// that credentials allow writing, reading and deleting under the prefix.
+pub async fn probe_bucket(config: &Config) -> Result<(), String> {
+    let bucket = config
+        .s3_bucket_name
+        .as_deref()
+        .expect("need s3 bucket name to probe remote");
+    let key = format!(
+        "{}/.cutter-selftest",
+        config.s3_prefixes.first().map_or("", String::as_str)
+    );
+    let probe = b"cutter selftest".to_vec();
+
+    let store = open_store(config, bucket, NonZeroUsize::new(1).unwrap()).await;
+    store
+        .put(&key, probe.clone(), "text/plain")
+        .await
+        .map_err(|err| format!("put {}: {}", key, err))?;
+
+    let local = std::env::temp_dir().join(format!("cutter-selftest-{}", process::id()));
+    let local = local.to_string_lossy();
+    let body = store
+        .get(&key, &local, 0, None)
+        .await
+        .and_then(|_| fs::read(local.as_ref()).map_err(|err| format!("{}: {}", local, err)));
+    let _ = fs::remove_file(local.as_ref());
+    let body = body.map_err(|err| format!("get {}: {}", key, err))?;
+
+    store
+        .delete(&key)
+        .await
+        .map_err(|err| format!("delete {}: {}", key, err))?;
+
+    if body != probe {
+        return Err(format!("{} came back different from what was written", key));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::cutter::store::LocalStore;
+
+    fn pattern() -> Regex {
+        Regex::new(DEFAULT_CROP_KEY_PATTERN).unwrap()
+    }
+
+    #[test]
+    fn source_with_underscore_is_not_a_crop() {
+        assert!(!is_crop_key("gallery/beach_sunset.jpg", &pattern()));
+        assert!(!is_crop_key("IMG_2001.jpg", &pattern()));
+    }
+
+    #[test]
+    fn sized_keys_are_crops() {
+        assert!(is_crop_key("gallery/beach_sunset_200x200.jpg", &pattern()));
+        assert!(is_crop_key("beach_sunset_200x200px_200w.jpg", &pattern()));
+        assert!(is_crop_key(
+            "beach_1920x1080px_1920w.0a1b2c3d.jpg",
+            &pattern()
+        ));
+        assert!(is_crop_key("beach_thumb.jpg", &pattern()));
+        assert!(is_crop_key("beach_200x200px_200w-1.jpg", &pattern()));
+    }
+
+    fn config(args: &[&str]) -> Config {
+        crate::tests::parse([&["cutter", "-p", "/tmp/cutter-src"], args].concat())
+    }
+
+    fn downloads(key: &str, all_keys: &[String], cfg: &Config) -> bool {
+        should_download(key, &crop_stems(all_keys, &cfg.crop_key_pattern), cfg)
+    }
+
+    fn keys(keys: &[&str]) -> Vec<String> {
+        keys.iter().map(|k| k.to_string()).collect()
+    }
+
+    #[test]
+    fn downloads_new_sources() {
+        let all_keys = keys(&["gallery/", "gallery/beach.jpg", "gallery/beach_sunset.jpg"]);
+        let cfg = config(&[]);
+        assert!(downloads("gallery/beach.jpg", &all_keys, &cfg));
+        assert!(downloads("gallery/beach_sunset.jpg", &all_keys, &cfg));
+    }
+
+    #[test]
+    fn skips_directory_markers_and_crops() {
+        let all_keys = keys(&["gallery/", "gallery/beach_200x200px_200w.jpg"]);
+        let cfg = config(&["--overwrite"]);
+        assert!(!downloads("gallery/", &all_keys, &cfg));
+        assert!(!downloads("", &all_keys, &cfg));
+        assert!(!downloads(
+            "gallery/beach_200x200px_200w.jpg",
+            &all_keys,
+            &cfg
+        ));
+        assert!(!downloads("gallery/beach_thumb.jpg", &all_keys, &cfg));
+    }
+
+    #[test]
+    fn skips_processed_sources_unless_overwriting() {
+        let all_keys = keys(&[
+            "gallery/beach.jpg",
+            "gallery/beach_200x200px_200w.jpg",
+            "gallery/beach_400x400px_400w.jpg",
+            "gallery/forest.jpg",
+        ]);
+        assert!(!downloads("gallery/beach.jpg", &all_keys, &config(&[])));
+        assert!(downloads("gallery/forest.jpg", &all_keys, &config(&[])));
+        assert!(downloads(
+            "gallery/beach.jpg",
+            &all_keys,
+            &config(&["--overwrite"])
+        ));
+    }
+
+    #[test]
+    fn keys_outside_the_download_directory_are_unsafe() {
+        assert!(is_safe_key("gallery/a.jpg"));
+        assert!(is_safe_key("gallery/a..b.jpg"));
+        assert!(!is_safe_key("../../etc/x"));
+        assert!(!is_safe_key("gallery/../../x"));
+        assert!(!is_safe_key("/abs"));
+        assert!(!is_safe_key("..\\x"));
+    }
+
+    #[test]
+    fn namespaces_downloads_by_prefix() {
+        assert_eq!(
+            namespaced_file_path("/tmp/src", "weddings", "weddings/beach.jpg"),
+            "/tmp/src/weddings/beach.jpg"
+        );
+        assert_eq!(
+            namespaced_file_path("/tmp/src", "2022/trips/", "2022/trips/oslo/fjord.jpg"),
+            "/tmp/src/2022/trips/oslo/fjord.jpg"
+        );
+    }
+
+    #[test]
+    fn tags_are_checked_and_encoded() {
+        assert_eq!(
+            str_to_tag("type=thumbnail"),
+            Ok(("type".to_string(), "thumbnail".to_string()))
+        );
+        assert!(str_to_tag("type").is_err());
+        assert!(str_to_tag("aws:type=x").is_err());
+        assert!(str_to_tag("type=a&b").is_err());
+
+        let tags = [
+            str_to_tag("gallery=2023/summer").unwrap(),
+            str_to_tag("kind=a b").unwrap(),
+        ];
+        assert_eq!(tagging(&tags), "gallery=2023%2Fsummer&kind=a%20b");
+    }
+
+    #[test]
+    fn kms_key_ids_are_checked() {
+        for key in [
+            "arn:aws:kms:eu-west-1:123456789012:key/1234abcd-12ab-34cd-56ef-1234567890ab",
+            "arn:aws-us-gov:kms:us-gov-west-1:123456789012:alias/crops",
+            "1234abcd-12ab-34cd-56ef-1234567890ab",
+            "alias/crops",
+            "arn:aws:kms:us-east-1:111122223333:key/mrk-1234abcd12ab34cd56ef1234567890ab",
+            "mrk-1234abcd12ab34cd56ef1234567890ab",
+        ] {
+            assert_eq!(str_to_kms_key_id(key), Ok(key.to_string()));
+        }
+        assert!(str_to_kms_key_id("arn:aws:kms:eu-west-1:1234:key/abc").is_err());
+        assert!(str_to_kms_key_id("arn:aws:s3:::bucket").is_err());
+        assert!(str_to_kms_key_id("crops").is_err());
+        assert!(str_to_kms_key_id("mrk-1234").is_err());
+        assert!(str_to_kms_key_id("key/1234abcd-12ab-34cd-56ef-1234567890ab").is_err());
+    }
+
+    #[test]
+    fn sniffs_content_type_from_magic_bytes() {
+        assert_eq!(
+            sniff_content_type(&[0xff, 0xd8, 0xff, 0xe0]),
+            Some("image/jpeg")
+        );
+        assert_eq!(sniff_content_type(b"\x89PNG\r\n\x1a\n"), Some("image/png"));
+        assert_eq!(
+            sniff_content_type(b"RIFF\0\0\0\0WEBPVP8 "),
+            Some("image/webp")
+        );
+        assert_eq!(
+            sniff_content_type(b"\0\0\0\x1cftypavif"),
+            Some("image/avif")
+        );
+        assert_eq!(sniff_content_type(b"hello"), None);
+    }
+
+    #[tokio::test]
+    async fn partial_downloads_of_another_version_are_restarted() {
+        let root = std::env::temp_dir().join(format!("cutter-test-resume-etag-{}", process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let store = LocalStore::new(&root.join("bucket").to_string_lossy());
+        store
+            .put("a.jpg", b"abc".to_vec(), "image/jpeg")
             .await
-            .expect("failed to read file contents");
-        // @ToDo: Fix output if files are served locally.
-        // They're currently prefixed with the folder name sent in through config
-        // But need the prefix from S3.
-        let file_name = Path::new(file)
-            .file_name()
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .to_owned();
-
-        let s3_file_path = format!("{}/{}", prefix, &file_name);
-        client
-            .put_object()
-            .bucket(bucket)
-            .key(s3_file_path)
-            .body(body)
-            .send()
+            .unwrap();
+        let path = root.join("a.jpg").to_string_lossy().into_owned();
+        let part = format!("{}{}", path, PARTIAL_DOWNLOAD_SUFFIX);
+        let e_tag = format!("{}.etag{}", path, PARTIAL_DOWNLOAD_SUFFIX);
+
+        // Started from the listed version, so only the rest is fetched.
+        fs::write(&part, b"a").unwrap();
+        fs::write(&e_tag, "\"1\"").unwrap();
+        download_object(&store, "a.jpg", &path, Some("\"1\""), true)
             .await
-            .expect("failed to upload");
-        counter += 1;
+            .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"abc");
+        assert!(!Path::new(&e_tag).exists());
+
+        // Started from an older version, so the whole object is fetched.
+        fs::write(&part, b"xyz").unwrap();
+        fs::write(&e_tag, "\"0\"").unwrap();
+        download_object(&store, "a.jpg", &path, Some("\"1\""), true)
+            .await
+            .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"abc");
+
+        let _ = fs::remove_dir_all(&root);
     }
 }
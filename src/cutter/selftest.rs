@@ -0,0 +1,84 @@
+use std::fs;
+
+use super::imageprocessing::{save_image, transform_image_bytes, Size, TransformError};
+use super::s3::probe_bucket;
+use super::util::check_writable;
+use crate::Config;
+
+/// Tiny images decoded to check that the format support cutter relies on
+/// was compiled in.
+const SAMPLES: [(&str, &[u8]); 2] = [
+    ("jpeg", include_bytes!("../../tests/fixtures/landscape.jpg")),
+    ("png", include_bytes!("../../tests/fixtures/portrait.png")),
+];
+
+/// Checks everything a real run with `config` needs: decoding, cropping to
+/// each configured size, the source directory when one is given, the output
+/// directory and, when a bucket is configured, S3 access. Prints OK/FAIL per check and returns
+/// whether all of them passed.
+pub async fn selftest(config: &Config) -> bool {
+    let mut results = Vec::new();
+
+    if config.files_path.is_some() || config.local_root.is_some() {
+        results.push((
+            format!("read {}", config.source_dir()),
+            fs::read_dir(config.source_dir())
+                .map(|_| ())
+                .map_err(|err| err.to_string()),
+        ));
+    }
+
+    let writable =
+        fs::create_dir_all(&config.tmp_dir).and_then(|_| check_writable(&config.tmp_dir));
+    let can_write = writable.is_ok();
+    results.push((
+        format!("write {}", config.tmp_dir),
+        writable.map_err(|err| err.to_string()),
+    ));
+
+    for (format, data) in SAMPLES {
+        results.push((
+            format!("decode {}", format),
+            transform_image_bytes(data, &config.transform_options().with_size(Size(8, 8)))
+                .map(|_| ())
+                .map_err(|err| err.to_string()),
+        ));
+    }
+
+    for size in &config.crop_sizes {
+        let (_, data) = SAMPLES[0];
+        let crop = transform_image_bytes(data, &config.transform_options().with_size(*size))
+            .and_then(|image| {
+                if !can_write {
+                    return Ok(());
+                }
+                let (path, _) = save_image(
+                    &image,
+                    &config.tmp_dir,
+                    "cutter-selftest",
+                    "jpg",
+                    &config.encode_options(),
+                    None,
+                    None,
+                    config.size_label,
+                )?;
+                fs::remove_file(path).map_err(|err| TransformError::IoError(err.to_string()))
+            });
+        results.push((
+            format!("crop {}x{}", size.0, size.1),
+            crop.map_err(|err| err.to_string()),
+        ));
+    }
+
+    if let Some(bucket) = &config.s3_bucket_name {
+        results.push((format!("s3 bucket {}", bucket), probe_bucket(config).await));
+    }
+
+    for (check, result) in &results {
+        match result {
+            Ok(()) => println!("OK   {}", check),
+            Err(err) => println!("FAIL {}: {}", check, err),
+        }
+    }
+    results.iter().all(|(_, result)| result.is_ok())
+}
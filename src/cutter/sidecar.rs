@@ -0,0 +1,174 @@
+use std::fs;
+use std::path::Path;
+
+use super::imageprocessing::{
+    dedupe_sizes, scale_size, str_to_flip, str_to_rotation, str_to_size, Flip, Rotation, Size,
+    TransformOptions,
+};
+
+/// Suffix of the per-file override file placed next to a source,
+/// e.g. `foo.jpg.cutter.toml`.
+pub const SIDECAR_SUFFIX: &str = ".cutter.toml";
+
+/// Per-file overrides read from a source's sidecar file.
+///
+/// Only a small TOML subset is understood: `key = value` lines where values
+/// are strings, booleans or arrays of strings, plus `#` comments.
+///
+/// ```toml
+/// sizes = ["2560x1440"]
+/// replace_sizes = false
+/// rotate = "90"
+/// flip = "horizontal"
+/// ```
+#[derive(Debug, Default)]
+pub struct Sidecar {
+    pub sizes: Vec<Size>,
+    pub replace_sizes: bool,
+    pub rotate: Option<Rotation>,
+    pub flip: Option<Flip>,
+}
+
+impl Sidecar {
+    /// Crop sizes for the file: the global `sizes` extended with, or replaced
+    /// by, the sizes from the sidecar scaled by `scale`, sorted and without
+    /// repeats like the global ones.
+    pub fn crop_sizes(&self, sizes: &[Size], scale: Option<f64>) -> Result<Vec<Size>, String> {
+        let mut crop_sizes = if self.replace_sizes {
+            Vec::new()
+        } else {
+            sizes.to_vec()
+        };
+        for size in &self.sizes {
+            crop_sizes.push(match scale {
+                Some(factor) => scale_size(*size, factor)?,
+                None => *size,
+            });
+        }
+        dedupe_sizes(&mut crop_sizes);
+        Ok(crop_sizes)
+    }
+
+    /// Transform options for the file: the global `options` with any
+    /// overrides from the sidecar applied.
+    pub fn options(&self, options: &TransformOptions) -> TransformOptions {
+        TransformOptions {
+            rotate: self.rotate.unwrap_or(options.rotate),
+            flip: self.flip.unwrap_or(options.flip),
+            ..*options
+        }
+    }
+}
+
+/// Loads the sidecar for `source`, if there is one.
+pub fn load_sidecar(source: &str) -> Result<Option<Sidecar>, String> {
+    let path = format!("{}{}", source, SIDECAR_SUFFIX);
+    if !Path::new(&path).is_file() {
+        return Ok(None);
+    }
+    let contents = fs::read_to_string(&path).map_err(|err| format!("{}: {}", path, err))?;
+    parse_sidecar(&contents)
+        .map(Some)
+        .map_err(|err| format!("{}: {}", path, err))
+}
+
+fn parse_sidecar(contents: &str) -> Result<Sidecar, String> {
+    let mut sidecar = Sidecar::default();
+    for (lineno, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("line {}: expected 'key = value'", lineno + 1))?;
+        let value = value.trim();
+        match key.trim() {
+            "sizes" => {
+                sidecar.sizes = parse_string_array(value)
+                    .ok_or_else(|| format!("line {}: expected array of strings", lineno + 1))?
+                    .iter()
+                    .map(|s| str_to_size(s))
+                    .collect::<Result<_, _>>()?;
+            }
+            "replace_sizes" => sidecar.replace_sizes = parse_bool(value, lineno)?,
+            "rotate" => {
+                let rotate = parse_string(value)
+                    .ok_or_else(|| format!("line {}: expected string", lineno + 1))?;
+                sidecar.rotate = Some(str_to_rotation(rotate)?);
+            }
+            "flip" => {
+                let flip = parse_string(value)
+                    .ok_or_else(|| format!("line {}: expected string", lineno + 1))?;
+                sidecar.flip = Some(str_to_flip(flip)?);
+            }
+            key => return Err(format!("line {}: unknown key '{}'", lineno + 1, key)),
+        }
+    }
+    Ok(sidecar)
+}
+
+fn parse_bool(value: &str, lineno: usize) -> Result<bool, String> {
+    value
+        .parse()
+        .map_err(|_| format!("line {}: expected true or false", lineno + 1))
+}
+
+fn parse_string(value: &str) -> Option<&str> {
+    value.strip_prefix('"')?.strip_suffix('"')
+}
+
+fn parse_string_array(value: &str) -> Option<Vec<String>> {
+    let inner = value.strip_prefix('[')?.strip_suffix(']')?.trim();
+    if inner.is_empty() {
+        return Some(Vec::new());
+    }
+    inner
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(|item| parse_string(item).map(str::to_owned))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_overrides() {
+        let sidecar = parse_sidecar(
+            "# portrait scan\nsizes = [\"300x200\", \"100x100\"]\nreplace_sizes = true\nrotate = \"90\"\nflip = \"vertical\"\n",
+        )
+        .unwrap();
+        assert_eq!(sidecar.sizes, [Size(300, 200), Size(100, 100)]);
+        assert!(sidecar.replace_sizes);
+        assert_eq!(sidecar.rotate, Some(Rotation::Rotate90));
+        assert_eq!(sidecar.flip, Some(Flip::Vertical));
+    }
+
+    #[test]
+    fn malformed_sidecars_are_errors() {
+        assert!(parse_sidecar("sizes = [\"2560\"]").is_err());
+        assert!(parse_sidecar("sizes = [\"0x100\"]").is_err());
+        assert!(parse_sidecar("sizes = \"100x100\"").is_err());
+        assert!(parse_sidecar("rotate = 90").is_err());
+        assert!(parse_sidecar("colour = \"red\"").is_err());
+        assert!(parse_sidecar("sizes").is_err());
+    }
+
+    #[test]
+    fn sizes_are_scaled_and_deduped() {
+        let sidecar = parse_sidecar("sizes = [\"100x100\", \"50x50\"]").unwrap();
+        let global = [Size(100, 100), Size(200, 200)];
+        assert_eq!(
+            sidecar.crop_sizes(&global, None).unwrap(),
+            [Size(50, 50), Size(100, 100), Size(200, 200)]
+        );
+        assert_eq!(
+            sidecar.crop_sizes(&global, Some(2.0)).unwrap(),
+            [Size(100, 100), Size(200, 200)]
+        );
+        assert!(sidecar.crop_sizes(&global, Some(1000.0)).is_err());
+    }
+}
@@ -0,0 +1,29 @@
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use super::log;
+
+/// Exit code used when a run is cut short by Ctrl-C, as a shell would.
+pub const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Catches Ctrl-C so in-flight work can wind down instead of being lost.
+/// A second Ctrl-C exits immediately.
+pub fn install_interrupt_handler() {
+    tokio::spawn(async {
+        if tokio::signal::ctrl_c().await.is_err() {
+            return;
+        }
+        log::warn("Interrupted, finishing in-flight work (press Ctrl-C again to exit now)");
+        INTERRUPTED.store(true, Ordering::SeqCst);
+
+        if tokio::signal::ctrl_c().await.is_ok() {
+            process::exit(INTERRUPTED_EXIT_CODE);
+        }
+    });
+}
+
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
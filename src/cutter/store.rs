@@ -0,0 +1,256 @@
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::{Seek, SeekFrom};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use super::s3::{S3Store, Storage};
+use crate::Config;
+
+/// An object as listed by an `ObjectStore`.
+#[derive(Debug, Clone)]
+pub struct ObjectInfo {
+    pub key: String,
+    pub size: i64,
+    /// For single-part uploads, the quoted MD5 of the contents.
+    pub e_tag: Option<String>,
+    /// Seconds since the epoch.
+    pub last_modified: Option<i64>,
+}
+
+/// A bucket sources are downloaded from and crops are uploaded to.
+pub trait ObjectStore {
+    /// Every object whose key starts with `prefix`.
+    async fn list(&self, prefix: Option<&str>) -> Result<Vec<ObjectInfo>, String>;
+
+    /// Writes the object `key` to the file at `path`. With a non-zero
+    /// `offset`, the object from there on is appended if the store supports
+    /// ranged reads and the object still has the ETag `if_match`; otherwise
+    /// the file is replaced with the whole object.
+    async fn get(
+        &self,
+        key: &str,
+        path: &str,
+        offset: u64,
+        if_match: Option<&str>,
+    ) -> Result<(), String>;
+
+    /// Stores `body` as the object `key`.
+    async fn put(&self, key: &str, body: Vec<u8>, content_type: &str) -> Result<(), String>;
+
+    async fn delete(&self, key: &str) -> Result<(), String>;
+}
+
+/// The store picked with `--storage`.
+pub enum Store {
+    S3(S3Store),
+    Local(LocalStore),
+}
+
+/// Opens `bucket` in the configured storage, sending at most `concurrency`
+/// requests at once. For `--storage local` the bucket is a directory.
+pub async fn open_store(config: &Config, bucket: &str, concurrency: NonZeroUsize) -> Store {
+    match config.storage {
+        Storage::S3 | Storage::Gcs => {
+            Store::S3(S3Store::connect(config, bucket, concurrency).await)
+        }
+        Storage::Local => Store::Local(LocalStore::new(bucket)),
+    }
+}
+
+impl ObjectStore for Store {
+    async fn list(&self, prefix: Option<&str>) -> Result<Vec<ObjectInfo>, String> {
+        match self {
+            Store::S3(store) => store.list(prefix).await,
+            Store::Local(store) => store.list(prefix).await,
+        }
+    }
+
+    async fn get(
+        &self,
+        key: &str,
+        path: &str,
+        offset: u64,
+        if_match: Option<&str>,
+    ) -> Result<(), String> {
+        match self {
+            Store::S3(store) => store.get(key, path, offset, if_match).await,
+            Store::Local(store) => store.get(key, path, offset, if_match).await,
+        }
+    }
+
+    async fn put(&self, key: &str, body: Vec<u8>, content_type: &str) -> Result<(), String> {
+        match self {
+            Store::S3(store) => store.put(key, body, content_type).await,
+            Store::Local(store) => store.put(key, body, content_type).await,
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), String> {
+        match self {
+            Store::S3(store) => store.delete(key).await,
+            Store::Local(store) => store.delete(key).await,
+        }
+    }
+}
+
+/// A directory standing in for a bucket, with each object a file at the
+/// path named by its key. Useful for mirrors synced by other tools, and for
+/// exercising the remote code paths without network or credentials.
+pub struct LocalStore {
+    root: PathBuf,
+}
+
+impl LocalStore {
+    pub fn new(root: &str) -> Self {
+        LocalStore {
+            root: PathBuf::from(root),
+        }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+}
+
+impl ObjectStore for LocalStore {
+    /// Objects are listed without an ETag, so uploads are only verified by size.
+    async fn list(&self, prefix: Option<&str>) -> Result<Vec<ObjectInfo>, String> {
+        if !self.root.is_dir() {
+            return Err(format!("{} is not a directory", self.root.display()));
+        }
+        let mut files = Vec::new();
+        walk(&self.root, &mut files).map_err(|err| format!("{}: {}", self.root.display(), err))?;
+        let mut objects = Vec::new();
+        for file in files {
+            let key = match file.strip_prefix(&self.root) {
+                Ok(relative) => relative
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/"),
+                Err(_) => continue,
+            };
+            if !key.starts_with(prefix.unwrap_or("")) {
+                continue;
+            }
+            let meta = fs::metadata(&file).map_err(|err| format!("{}: {}", file.display(), err))?;
+            objects.push(ObjectInfo {
+                key,
+                size: meta.len() as i64,
+                e_tag: None,
+                last_modified: meta
+                    .modified()
+                    .ok()
+                    .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                    .map(|since_epoch| since_epoch.as_secs() as i64),
+            });
+        }
+        Ok(objects)
+    }
+
+    /// Files have no ETag, so `if_match` is ignored.
+    async fn get(
+        &self,
+        key: &str,
+        path: &str,
+        offset: u64,
+        _if_match: Option<&str>,
+    ) -> Result<(), String> {
+        let source = self.path(key);
+        let mut reader =
+            fs::File::open(&source).map_err(|err| format!("{}: {}", source.display(), err))?;
+        let len = reader
+            .metadata()
+            .map_err(|err| format!("{}: {}", source.display(), err))?
+            .len();
+        let append = offset > 0 && offset <= len;
+        if append {
+            reader
+                .seek(SeekFrom::Start(offset))
+                .map_err(|err| format!("{}: {}", source.display(), err))?;
+        }
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .append(append)
+            .truncate(!append)
+            .open(path)
+            .map_err(|err| format!("{}: {}", path, err))?;
+        // Copied in chunks, so memory use doesn't grow with the object.
+        io::copy(&mut reader, &mut file)
+            .map(|_| ())
+            .map_err(|err| format!("{} to {}: {}", source.display(), path, err))
+    }
+
+    async fn put(&self, key: &str, body: Vec<u8>, _content_type: &str) -> Result<(), String> {
+        let path = self.path(key);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|err| format!("{}: {}", parent.display(), err))?;
+        }
+        fs::write(&path, body).map_err(|err| format!("{}: {}", path.display(), err))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), String> {
+        let path = self.path(key);
+        fs::remove_file(&path).map_err(|err| format!("{}: {}", path.display(), err))
+    }
+}
+
+/// Collects every file below `dir`. Unlike source discovery, nothing is
+/// skipped: crops and sidecars are objects too.
+fn walk(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            walk(&entry.path(), files)?;
+        } else {
+            files.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn local_store_round_trips_objects() {
+        let root = std::env::temp_dir().join("cutter-test-local-store");
+        let _ = fs::remove_dir_all(&root);
+        let store = LocalStore::new(&root.to_string_lossy());
+
+        store
+            .put("gallery/a.jpg", b"abc".to_vec(), "image/jpeg")
+            .await
+            .unwrap();
+        store
+            .put("gallery/a_200x200px_200w.jpg", b"de".to_vec(), "image/jpeg")
+            .await
+            .unwrap();
+        store
+            .put("other/b.jpg", b"de".to_vec(), "image/jpeg")
+            .await
+            .unwrap();
+        let mut objects = store.list(Some("gallery/")).await.unwrap();
+        objects.sort_by(|a, b| a.key.cmp(&b.key));
+        let listed: Vec<(&str, i64)> = objects.iter().map(|o| (o.key.as_str(), o.size)).collect();
+        assert_eq!(
+            listed,
+            [("gallery/a.jpg", 3), ("gallery/a_200x200px_200w.jpg", 2)]
+        );
+
+        // Resuming appends the rest of the object.
+        let local = root.join("a.jpg.part");
+        fs::write(&local, b"a").unwrap();
+        let local = local.to_string_lossy();
+        store.get("gallery/a.jpg", &local, 1, None).await.unwrap();
+        assert_eq!(fs::read(local.as_ref()).unwrap(), b"abc");
+
+        store.delete("gallery/a.jpg").await.unwrap();
+        assert_eq!(store.list(Some("gallery/")).await.unwrap().len(), 1);
+    }
+}
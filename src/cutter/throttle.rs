@@ -0,0 +1,197 @@
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use aws_sdk_s3::types::SdkError;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use super::log;
+
+/// Requests allowed in flight before any have succeeded.
+const INITIAL_LIMIT: usize = 2;
+/// Times a throttled request is retried before its error is returned.
+const MAX_RETRIES: u32 = 6;
+/// Backoff before the first retry, doubled for every retry after it.
+const BASE_BACKOFF: Duration = Duration::from_millis(200);
+/// Longest backoff between retries, unless S3 asks for a longer one.
+const MAX_BACKOFF: Duration = Duration::from_secs(20);
+
+/// Limits concurrent S3 requests, starting low and growing by one with every
+/// success up to the configured concurrency, and halving when S3 throttles
+/// a request (503 SlowDown or 429), so large syncs don't slow themselves
+/// down by hitting the bucket with everything at once.
+pub struct Throttle {
+    permits: Semaphore,
+    limit: Mutex<Limit>,
+}
+
+/// The current concurrency limit, and how many permits still have to be
+/// taken out of circulation after the limit was lowered.
+#[derive(Debug, PartialEq)]
+struct Limit {
+    current: usize,
+    max: usize,
+    shrink: usize,
+}
+
+impl Limit {
+    fn new(max: usize) -> Self {
+        Limit {
+            current: max.min(INITIAL_LIMIT),
+            max,
+            shrink: 0,
+        }
+    }
+
+    /// Whether a returned permit should be kept out of circulation.
+    fn forget(&mut self) -> bool {
+        if self.shrink > 0 {
+            self.shrink -= 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Raises the limit after a success. Returns whether a permit was added.
+    fn grow(&mut self) -> bool {
+        if self.shrink == 0 && self.current < self.max {
+            self.current += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Halves the limit after S3 throttled a request.
+    fn halve(&mut self) {
+        let lowered = (self.current / 2).max(1);
+        self.shrink += self.current - lowered;
+        self.current = lowered;
+    }
+}
+
+impl Throttle {
+    pub fn new(max: NonZeroUsize) -> Self {
+        let limit = Limit::new(max.get());
+        Throttle {
+            permits: Semaphore::new(limit.current),
+            limit: Mutex::new(limit),
+        }
+    }
+
+    /// Sends the request built by `request` once a permit is free, retrying
+    /// with jittered exponential backoff, or after the delay S3 gives in
+    /// `Retry-After`, while it is throttled.
+    pub async fn send<T, E, F, Fut>(&self, mut request: F) -> Result<T, SdkError<E>>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, SdkError<E>>>,
+    {
+        let mut attempt = 0;
+        loop {
+            let permit = self
+                .permits
+                .acquire()
+                .await
+                .expect("throttle semaphore is never closed");
+            let result = request().await;
+            let retry_after = match &result {
+                Err(err) if attempt < MAX_RETRIES => throttled(err),
+                _ => None,
+            };
+            self.release(permit, retry_after.is_none());
+            match retry_after {
+                Some(retry_after) => {
+                    tokio::time::sleep(backoff(attempt, retry_after)).await;
+                    attempt += 1;
+                }
+                None => return result,
+            }
+        }
+    }
+
+    fn release(&self, permit: SemaphorePermit<'_>, succeeded: bool) {
+        let mut limit = self.limit.lock().unwrap();
+        if !succeeded {
+            limit.halve();
+            log::warn(&format!(
+                "S3 is throttling requests, lowering concurrency to {}",
+                limit.current
+            ));
+        }
+        if limit.forget() {
+            permit.forget();
+        } else if succeeded && limit.grow() {
+            self.permits.add_permits(1);
+        }
+    }
+}
+
+/// If S3 throttled the request, how long it asked us to wait, if at all.
+fn throttled<E>(err: &SdkError<E>) -> Option<Option<Duration>> {
+    let raw = match err {
+        SdkError::ServiceError { raw, .. } => raw.http(),
+        _ => return None,
+    };
+    if !matches!(raw.status().as_u16(), 429 | 503) {
+        return None;
+    }
+    let retry_after = raw
+        .headers()
+        .get("retry-after")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+        .map(Duration::from_secs);
+    Some(retry_after)
+}
+
+/// Delay before retry `attempt`: a random share of the exponential backoff,
+/// so throttled requests don't all come back at once, but never shorter than
+/// what S3 asked for.
+fn backoff(attempt: u32, retry_after: Option<Duration>) -> Duration {
+    let ceiling = BASE_BACKOFF
+        .saturating_mul(1 << attempt.min(16))
+        .min(MAX_BACKOFF);
+    let jittered = ceiling.mul_f64(random_fraction());
+    retry_after.map_or(jittered, |retry_after| retry_after.max(jittered))
+}
+
+/// A number in [0, 1), good enough for spreading out retries.
+fn random_fraction() -> f64 {
+    let hash = RandomState::new().build_hasher().finish();
+    (hash >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limit_ramps_up_and_halves() {
+        let mut limit = Limit::new(5);
+        assert_eq!(limit.current, 2);
+        while limit.grow() {}
+        assert_eq!(limit.current, 5);
+
+        limit.halve();
+        assert_eq!((limit.current, limit.shrink), (2, 3));
+        // Returned permits are retired before the limit grows again.
+        assert!(!limit.grow());
+        assert!(limit.forget() && limit.forget() && limit.forget());
+        assert!(!limit.forget());
+        assert!(limit.grow());
+        assert_eq!(limit.current, 3);
+    }
+
+    #[test]
+    fn backoff_is_capped_and_honours_retry_after() {
+        for attempt in 0..10 {
+            assert!(backoff(attempt, None) <= MAX_BACKOFF);
+        }
+        assert!(backoff(0, Some(Duration::from_secs(30))) >= Duration::from_secs(30));
+    }
+}
@@ -1,37 +1,171 @@
-use std::cmp;
+use std::env;
+#[cfg(unix)]
+use std::ffi::CString;
 use std::fs;
-use std::path::Path;
+use std::io;
+use std::io::{IsTerminal, Write};
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 use std::str;
+use std::time::Duration;
 
-pub fn generate_thumb_path(path: &str, w: u32, h: u32, path_suffix: &str) -> String {
-    return format!("{}_{}x{}px_{}w.{}", path, w, h, w, path_suffix);
+use regex::Regex;
+
+use super::log;
+use super::s3::PARTIAL_DOWNLOAD_SUFFIX;
+use super::sidecar::SIDECAR_SUFFIX;
+
+/// How the dimensions in crop file names are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SizeLabel {
+    /// `200x200`
+    #[default]
+    Plain,
+    /// Zero-padded to this many digits, e.g. `0200x0200` for 4.
+    ZeroPadded(usize),
+}
+
+impl SizeLabel {
+    fn format(&self, n: u32) -> String {
+        match self {
+            SizeLabel::Plain => n.to_string(),
+            SizeLabel::ZeroPadded(digits) => format!("{:0width$}", n, width = digits),
+        }
+    }
+}
+
+pub fn str_to_size_label(s: &str) -> Result<SizeLabel, String> {
+    match s {
+        "plain" => Ok(SizeLabel::Plain),
+        _ => s
+            .strip_prefix("pad")
+            .and_then(|digits| digits.parse().ok())
+            .map(SizeLabel::ZeroPadded)
+            .ok_or_else(|| format!("size label format must be plain or padN, not '{}'", s)),
+    }
+}
+
+pub fn generate_thumb_path(
+    path: &str,
+    w: u32,
+    h: u32,
+    hash: Option<&str>,
+    path_suffix: &str,
+    label: SizeLabel,
+) -> String {
+    let (w, h) = (label.format(w), label.format(h));
+    match hash {
+        Some(hash) => format!("{}_{}x{}px_{}w.{}.{}", path, w, h, w, hash, path_suffix),
+        None => format!("{}_{}x{}px_{}w.{}", path, w, h, w, path_suffix),
+    }
 }
 
 // @ToDo: Skip if not .jpg
 pub fn get_file_name(path: &str) -> String {
-    return Path::new(path)
+    Path::new(path)
         .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// `path` with the first `-N` suffix before its extension that no file has
+/// yet, e.g. `beach_200x200px_200w-1.jpg`.
+pub fn unused_path(path: &str) -> String {
+    let (stem, extension) = match path.rsplit_once('.') {
+        Some((stem, extension)) if !extension.contains('/') => (stem, format!(".{}", extension)),
+        _ => (path, String::new()),
+    };
+    (1..)
+        .map(|n| format!("{}-{}{}", stem, n, extension))
+        .find(|candidate| !Path::new(candidate).exists())
         .unwrap()
-        .to_str()
-        .unwrap()
-        .to_owned();
 }
 
-pub fn get_files_in_dir(dirpath: String) -> Vec<String> {
+/// Lists the source files in `dirpath`, descending into subdirectories when
+/// `recursive` is set. Files named like crops, per `crop_key_pattern`, are
+/// left out.
+///
+/// Symlinks are only included when `follow_symlinks` is set, and only if they
+/// resolve to a regular file; dangling or cyclic links are skipped with a
+/// warning. Linked files keep the link's path, so output is named after the
+/// link while decoding reads the file it points to.
+pub fn get_files_in_dir(
+    dirpath: String,
+    follow_symlinks: bool,
+    recursive: bool,
+    crop_key_pattern: &Regex,
+) -> Vec<String> {
     let dir = Path::new(&dirpath);
     let mut files = Vec::new();
     if dir.is_dir() {
-        for entry in fs::read_dir(dir).unwrap() {
-            let filename = entry
-                .unwrap()
-                .path()
-                .into_os_string()
-                .into_string()
-                .unwrap();
-            // Skip filenames with _ in them as that's used to denote file sizes/formats.
-            // !! The 400D shot images with names IMG_num so they won't work with this :D
-            if !filename.contains('_') {
-                files.push(filename);
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(err) => {
+                log::warn(&format!("Skipping {}: {}", dir.display(), err));
+                return files;
+            }
+        };
+        for entry in entries {
+            let path = match entry {
+                Ok(entry) => entry.path(),
+                Err(err) => {
+                    log::warn(&format!("Skipping entry in {}: {}", dir.display(), err));
+                    continue;
+                }
+            };
+            let path_string = match utf8_path(path.clone()) {
+                Some(path_string) => path_string,
+                None => continue,
+            };
+
+            if path.is_symlink() {
+                if !follow_symlinks {
+                    log::warn(&format!("Skipping symlink {}", path.display()));
+                    continue;
+                }
+                match fs::canonicalize(&path) {
+                    Ok(target) if target.is_file() => {}
+                    Ok(target) => {
+                        log::warn(&format!(
+                            "Skipping symlink {} to non-file {}",
+                            path.display(),
+                            target.display()
+                        ));
+                        continue;
+                    }
+                    Err(err) => {
+                        log::warn(&format!(
+                            "Skipping broken symlink {}: {}",
+                            path.display(),
+                            err
+                        ));
+                        continue;
+                    }
+                }
+            } else if path.is_dir() {
+                if recursive {
+                    files.extend(get_files_in_dir(
+                        path_string,
+                        follow_symlinks,
+                        recursive,
+                        crop_key_pattern,
+                    ));
+                }
+                continue;
+            }
+
+            let path_str = path.to_string_lossy();
+            if path_str.ends_with(SIDECAR_SUFFIX) || path_str.ends_with(PARTIAL_DOWNLOAD_SUFFIX) {
+                continue;
+            }
+
+            let is_crop = path
+                .file_name()
+                .map(|name| crop_key_pattern.is_match(&name.to_string_lossy()))
+                .unwrap_or(false);
+            if !is_crop {
+                files.push(path_string);
             }
         }
     }
@@ -39,10 +173,656 @@ pub fn get_files_in_dir(dirpath: String) -> Vec<String> {
     files
 }
 
-pub fn print_list_iter_status(current: u32, len: u32, prefix: &str, verbose: bool) {
+/// `path` as a string, or `None` with a warning when it isn't valid UTF-8,
+/// since sources are tracked by their string path throughout.
+fn utf8_path(path: PathBuf) -> Option<String> {
+    path.into_os_string()
+        .into_string()
+        .map_err(|path| {
+            log::warn(&format!(
+                "Skipping {}: file name is not valid UTF-8",
+                Path::new(&path).display()
+            ))
+        })
+        .ok()
+}
+
+/// Whether `path` contains glob metacharacters.
+pub fn is_glob(path: &str) -> bool {
+    path.contains(['*', '?', '['])
+}
+
+/// The leading directories of a glob `pattern` that contain no
+/// metacharacters, where matching starts.
+pub fn glob_base(pattern: &str) -> String {
+    let literal: Vec<&str> = pattern
+        .split('/')
+        .take_while(|component| !is_glob(component))
+        .collect();
+    match literal.join("/") {
+        base if base.is_empty() && pattern.starts_with('/') => "/".to_owned(),
+        base if base.is_empty() => ".".to_owned(),
+        base => base,
+    }
+}
+
+/// Lists the files matching a glob `pattern`, where `*` and `?` match within
+/// a path component, `**` matches any number of directories and `[...]`
+/// matches a character class.
+pub fn expand_glob(pattern: &str) -> Result<Vec<String>, String> {
+    let regex = Regex::new(&glob_to_regex(pattern))
+        .map_err(|err| format!("invalid glob '{}': {}", pattern, err))?;
+    let base = glob_base(pattern);
+    let mut files = Vec::new();
+    walk_files(Path::new(&base), &mut files);
+
+    let mut matches: Vec<String> = files
+        .into_iter()
+        .filter_map(utf8_path)
+        .map(|path| {
+            // Walking "." yields "./a.jpg" for a pattern of "*.jpg".
+            match (base.as_str(), path.strip_prefix("./")) {
+                (".", Some(relative)) if !pattern.starts_with("./") => relative.to_owned(),
+                _ => path,
+            }
+        })
+        .filter(|path| regex.is_match(path))
+        .collect();
+    matches.sort();
+    Ok(matches)
+}
+
+fn glob_to_regex(pattern: &str) -> String {
+    let mut regex = String::from("^");
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    regex.push_str("(?:.*/)?");
+                } else {
+                    regex.push_str(".*");
+                }
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => {
+                regex.push('[');
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    regex.push('^');
+                }
+                for c in chars.by_ref() {
+                    if c == ']' {
+                        break;
+                    }
+                    if c == '\\' || c == '[' {
+                        regex.push('\\');
+                    }
+                    regex.push(c);
+                }
+                regex.push(']');
+            }
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+/// Collects every regular file below `dir`, without following symlinked
+/// directories.
+fn walk_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => walk_files(&path, files),
+            Ok(_) if path.is_file() => files.push(path),
+            _ => {}
+        }
+    }
+}
+
+/// Directory of `source` relative to `source_root`, with `strip_prefix`
+/// removed from the front if it matches. Crops of `source` are written to
+/// this directory under the output directory.
+pub fn relative_source_dir(source: &str, source_root: &str, strip_prefix: Option<&str>) -> PathBuf {
+    let parent = Path::new(source).parent().unwrap_or_else(|| Path::new(""));
+    let relative = parent.strip_prefix(source_root).unwrap_or(parent);
+    let relative = match strip_prefix {
+        Some(prefix) => relative.strip_prefix(prefix).unwrap_or(relative),
+        None => relative,
+    };
+    if relative.is_absolute() {
+        // Sources outside the root keep only their file name.
+        PathBuf::new()
+    } else {
+        relative.to_path_buf()
+    }
+}
+
+/// Joins the components of `relative_dir` and `file_name` with dashes, e.g.
+/// "2023/vacation" and "img" become "2023-vacation-img". Dashes and percent
+/// signs within a component are percent-encoded, so different layouts can't
+/// flatten to the same name: "a/b" becomes "a-b", but "a-b" becomes "a%2Db".
+pub fn flattened_file_name(relative_dir: &Path, file_name: &str) -> String {
+    let escape = |component: &str| component.replace('%', "%25").replace('-', "%2D");
+    relative_dir
+        .components()
+        .map(|c| escape(&c.as_os_str().to_string_lossy()))
+        .chain([escape(file_name)])
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Prints `current` out of `len` every `every` items, or by default every
+/// quarter of `len`, at most every 25 items. `verbose` prints every item.
+pub fn print_list_iter_status(
+    current: u32,
+    len: u32,
+    prefix: &str,
+    verbose: bool,
+    every: Option<NonZeroU32>,
+    eta: Option<Duration>,
+) {
     let total = len;
-    let threshold = cmp::max(1, cmp::min(25, len * 25 / 100));
-    if verbose || (current == 0 || current == total || current % threshold == 0) {
-        println!("{} {}/{}", prefix, current, total);
+    let threshold = every.map_or((len * 25 / 100).clamp(1, 25), NonZeroU32::get);
+    if verbose || (current == 0 || current == total || current.is_multiple_of(threshold)) {
+        match eta {
+            Some(eta) if current < total => log::plain(&format!(
+                "{} {}/{} ETA ~{}",
+                prefix,
+                current,
+                total,
+                format_duration(eta)
+            )),
+            _ => log::plain(&format!("{} {}/{}", prefix, current, total)),
+        }
+    }
+}
+
+/// Formats `duration` to the second, e.g. `3m20s` or `1h05m`.
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match (seconds / 3600, seconds / 60 % 60, seconds % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m{:02}s", m, s),
+        (h, m, _) => format!("{}h{:02}m", h, m),
+    }
+}
+
+/// Splits a command line into arguments on whitespace, like a shell would
+/// but without expanding anything. Single quotes keep their contents as is,
+/// and a backslash escapes the next character outside them.
+pub fn split_command(cmd: &str) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    let mut arg: Option<String> = None;
+    let mut chars = cmd.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => args.extend(arg.take()),
+            '\'' => {
+                let arg = arg.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => arg.push(c),
+                        None => return Err(format!("unterminated ' in '{}'", cmd)),
+                    }
+                }
+            }
+            '"' => {
+                let arg = arg.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c) => arg.push(c),
+                            None => return Err(format!("unterminated \" in '{}'", cmd)),
+                        },
+                        Some(c) => arg.push(c),
+                        None => return Err(format!("unterminated \" in '{}'", cmd)),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                Some(c) => arg.get_or_insert_with(String::new).push(c),
+                None => return Err(format!("trailing \\ in '{}'", cmd)),
+            },
+            c => arg.get_or_insert_with(String::new).push(c),
+        }
+    }
+    args.extend(arg);
+    if args.is_empty() {
+        return Err("empty post-process command".to_string());
+    }
+    Ok(args)
+}
+
+/// Runs the external command `cmd` on the file at `path`.
+///
+/// `cmd` is split into arguments by `split_command` and not passed through
+/// a shell. Every `{}` in an argument is replaced by `path`; without any,
+/// `path` is appended as the last argument.
+pub fn run_post_process(cmd: &str, path: &str) -> Result<(), String> {
+    let mut args = split_command(cmd)?;
+    if !args.iter().any(|arg| arg.contains("{}")) {
+        args.push("{}".to_owned());
+    }
+    let args: Vec<String> = args.iter().map(|arg| arg.replace("{}", path)).collect();
+    let (program, args) = args
+        .split_first()
+        .expect("split_command returns an argument");
+
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|err| format!("{}: {}", program, err))?;
+    if !output.status.success() {
+        return Err(format!(
+            "{} exited with {}: {}",
+            program,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+/// Checks that files can be created in `dir` by writing and removing a probe file.
+pub fn check_writable(dir: &str) -> io::Result<()> {
+    let probe = Path::new(dir).join(".cutter-write-probe");
+    fs::write(&probe, b"probe")?;
+    fs::remove_file(&probe)
+}
+
+/// Whether one of `a` and `b` is, or lies inside, the other.
+pub fn paths_overlap(a: &str, b: &str) -> bool {
+    let resolve = |p: &str| fs::canonicalize(p).unwrap_or_else(|_| PathBuf::from(p));
+    let (a, b) = (resolve(a), resolve(b));
+    a.starts_with(&b) || b.starts_with(&a)
+}
+
+/// Whether `dir` is the filesystem root, the home directory or one of its
+/// ancestors, none of which cutter should ever remove.
+pub fn is_dangerous_to_remove(dir: &str) -> bool {
+    let dir = fs::canonicalize(dir).unwrap_or_else(|_| PathBuf::from(dir));
+    if dir.parent().is_none() {
+        return true;
+    }
+    match env::var_os("HOME").map(|home| fs::canonicalize(&home).unwrap_or_else(|_| home.into())) {
+        Some(home) => home.starts_with(&dir),
+        None => false,
+    }
+}
+
+/// Whether `dir` lies inside the system temp directory.
+pub fn is_temp_path(dir: &str) -> bool {
+    let temp = env::temp_dir();
+    let temp = fs::canonicalize(&temp).unwrap_or(temp);
+    let dir = fs::canonicalize(dir).unwrap_or_else(|_| PathBuf::from(dir));
+    dir != temp && dir.starts_with(&temp)
+}
+
+/// Removes `dir` and everything in it, refusing for the filesystem root and
+/// the home directory, and asking first outside the temp directory unless
+/// `assume_yes` is set.
+pub fn remove_dir_guarded(dir: &str, assume_yes: bool) -> Result<(), String> {
+    if is_dangerous_to_remove(dir) {
+        return Err(format!("refusing to remove {}", dir));
+    }
+    if !assume_yes
+        && !is_temp_path(dir)
+        && !confirm(&format!("Remove {} and everything in it? [y/N] ", dir))
+    {
+        return Err(format!("not removing {}, aborting", dir));
+    }
+    fs::remove_dir_all(dir).map_err(|err| format!("{}: {}", dir, err))
+}
+
+/// Asks `question` on the terminal and returns whether the answer was yes.
+/// Without a terminal to ask on, the answer is no.
+pub fn confirm(question: &str) -> bool {
+    if !io::stdin().is_terminal() {
+        return false;
+    }
+    // On stderr, so the prompt doesn't end up in `--output json`.
+    eprint!("{}", question);
+    let mut answer = String::new();
+    if io::stderr().flush().is_err() || io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+/// Lists the files under `dir` whose names match `pattern`, i.e. the crops
+/// left by an earlier run.
+pub fn get_crops_in_dir(dir: &str, pattern: &Regex) -> Vec<String> {
+    let mut files = Vec::new();
+    walk_files(Path::new(dir), &mut files);
+    let mut crops: Vec<String> = files
+        .into_iter()
+        .filter(|path| {
+            path.file_name()
+                .map(|name| pattern.is_match(&name.to_string_lossy()))
+                .unwrap_or(false)
+        })
+        .filter_map(utf8_path)
+        .collect();
+    crops.sort();
+    crops
+}
+
+/// Removes files under `dir` whose names match `pattern`, leaving everything
+/// else in place. Returns how many files were removed.
+pub fn remove_crops(dir: &str, pattern: &Regex) -> io::Result<usize> {
+    let mut removed = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            removed += remove_crops(&path.to_string_lossy(), pattern)?;
+        } else if (file_type.is_file() || file_type.is_symlink())
+            && pattern.is_match(&entry.file_name().to_string_lossy())
+        {
+            fs::remove_file(&path)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Bytes available to unprivileged users on the filesystem holding `dir`.
+#[cfg(unix)]
+pub fn available_space(dir: &str) -> Option<u64> {
+    let path = CString::new(dir).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    #[allow(clippy::unnecessary_cast)]
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+pub fn available_space(_dir: &str) -> Option<u64> {
+    None
+}
+
+/// Parses an octal permission mode such as `644` or `0755`.
+pub fn str_to_mode(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| format!("mode must be octal, e.g. 0644, not '{}'", s))
+}
+
+/// Sets the permission bits of `path` to `mode`, if given.
+#[cfg(unix)]
+pub fn set_mode(path: &Path, mode: Option<u32>) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    match mode {
+        Some(mode) => fs::set_permissions(path, fs::Permissions::from_mode(mode)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn set_mode(_path: &Path, _mode: Option<u32>) -> io::Result<()> {
+    Ok(())
+}
+
+/// Creates `dir` and its missing parents, setting `mode` on each directory
+/// from `root` down to `dir`.
+pub fn create_dir_with_mode(dir: &Path, root: &str, mode: Option<u32>) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    for ancestor in dir.ancestors().take_while(|a| a.starts_with(root)) {
+        set_mode(ancestor, mode)?;
+    }
+    Ok(())
+}
+
+/// Quotes and escapes `s` as a JSON string.
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::cutter::s3::DEFAULT_CROP_KEY_PATTERN;
+
+    fn pattern() -> Regex {
+        Regex::new(DEFAULT_CROP_KEY_PATTERN).unwrap()
+    }
+
+    fn thumb_path(source: &str, w: u32, h: u32) -> String {
+        generate_thumb_path(&get_file_name(source), w, h, None, "jpg", SizeLabel::Plain)
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_file_names_are_skipped() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = env::temp_dir().join(format!("cutter-test-utf8-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(OsStr::from_bytes(b"caf\xe9.jpg")), b"").unwrap();
+        fs::write(dir.join("caf\u{e9}.jpg"), b"").unwrap();
+
+        let files = get_files_in_dir(dir.to_string_lossy().into_owned(), false, false, &pattern());
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            files,
+            vec![dir.join("caf\u{e9}.jpg").to_string_lossy().into_owned()]
+        );
+    }
+
+    #[test]
+    fn sources_with_underscores_are_listed_but_crops_are_not() {
+        let dir = env::temp_dir().join(format!("cutter-test-underscore-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("beach_sunset.jpg"), b"").unwrap();
+        fs::write(dir.join("beach_sunset_200x200px_200w.jpg"), b"").unwrap();
+
+        let files = get_files_in_dir(dir.to_string_lossy().into_owned(), false, false, &pattern());
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            files,
+            vec![dir.join("beach_sunset.jpg").to_string_lossy().into_owned()]
+        );
+    }
+
+    #[test]
+    fn durations_are_formatted_to_the_second() {
+        assert_eq!(format_duration(Duration::from_millis(4_900)), "4s");
+        assert_eq!(format_duration(Duration::from_secs(200)), "3m20s");
+        assert_eq!(format_duration(Duration::from_secs(3_900)), "1h05m");
+    }
+
+    #[test]
+    fn commands_are_split_like_a_shell() {
+        assert_eq!(
+            split_command("jpegoptim  --strip-all {}").unwrap(),
+            ["jpegoptim", "--strip-all", "{}"]
+        );
+        assert_eq!(
+            split_command(r#"'/opt/my tools/opt' --out="a \"b\"" c\ d ''"#).unwrap(),
+            ["/opt/my tools/opt", "--out=a \"b\"", "c d", ""]
+        );
+        assert!(split_command("opt 'unterminated").is_err());
+        assert!(split_command("   ").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn post_process_substitutes_within_arguments() {
+        let dir = env::temp_dir().join(format!("cutter-test-post {}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("a b.jpg");
+        fs::write(&path, b"crop").unwrap();
+        let path = path.to_string_lossy();
+
+        run_post_process("cp {} '{}.copy'", &path).unwrap();
+        let copied = fs::read(format!("{}.copy", path));
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(copied.unwrap(), b"crop");
+    }
+
+    #[test]
+    fn unused_paths_count_up() {
+        let dir = env::temp_dir().join(format!("cutter-test-unused-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir
+            .join("beach_200x200px_200w.jpg")
+            .to_string_lossy()
+            .into_owned();
+        fs::write(&path, b"").unwrap();
+
+        let first = unused_path(&path);
+        assert!(first.ends_with("beach_200x200px_200w-1.jpg"), "{}", first);
+        fs::write(&first, b"").unwrap();
+        let second = unused_path(&path);
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(second.ends_with("beach_200x200px_200w-2.jpg"), "{}", second);
+    }
+
+    #[test]
+    fn modes_are_parsed_as_octal() {
+        assert_eq!(str_to_mode("644"), Ok(0o644));
+        assert_eq!(str_to_mode("0755"), Ok(0o755));
+        assert!(str_to_mode("0899").is_err());
+        assert!(str_to_mode("17777").is_err());
+    }
+
+    #[test]
+    fn file_name_drops_only_the_last_extension() {
+        assert_eq!(get_file_name("gallery/my.photo.v2.jpg"), "my.photo.v2");
+        assert_eq!(get_file_name("gallery/beach.JPG"), "beach");
+        assert_eq!(get_file_name("gallery/no-extension"), "no-extension");
+    }
+
+    #[test]
+    fn thumb_path_keeps_dots_spaces_and_unicode() {
+        assert_eq!(
+            thumb_path("my.photo.v2.jpg", 200, 200),
+            "my.photo.v2_200x200px_200w.jpg"
+        );
+        assert_eq!(
+            thumb_path("summer holiday.jpg", 400, 300),
+            "summer holiday_400x300px_400w.jpg"
+        );
+        assert_eq!(
+            thumb_path("Tromsø nordlys 🌌.png", 800, 600),
+            "Tromsø nordlys 🌌_800x600px_800w.jpg"
+        );
+        assert_eq!(
+            generate_thumb_path(
+                "my.photo.v2",
+                200,
+                200,
+                Some("0a1b2c3d"),
+                "jpg",
+                SizeLabel::Plain
+            ),
+            "my.photo.v2_200x200px_200w.0a1b2c3d.jpg"
+        );
+    }
+
+    #[test]
+    fn thumb_path_pads_size_labels() {
+        let label = str_to_size_label("pad4").unwrap();
+        assert_eq!(
+            generate_thumb_path("beach", 200, 1080, None, "jpg", label),
+            "beach_0200x1080px_0200w.jpg"
+        );
+        assert_eq!(
+            generate_thumb_path("beach", 12345, 200, None, "jpg", label),
+            "beach_12345x0200px_12345w.jpg"
+        );
+        assert!(str_to_size_label("padding").is_err());
+    }
+
+    #[test]
+    fn thumb_paths_do_not_collide() {
+        let sources = [
+            "my.photo.jpg",
+            "my.photo.v2.jpg",
+            "my photo.jpg",
+            "my_photo.jpg",
+        ];
+        let sizes = [(200, 200), (200, 300), (300, 200)];
+        let paths: Vec<String> = sources
+            .iter()
+            .flat_map(|source| sizes.iter().map(move |&(w, h)| thumb_path(source, w, h)))
+            .collect();
+        let unique: HashSet<&String> = paths.iter().collect();
+        assert_eq!(unique.len(), paths.len());
+
+        for path in &paths {
+            assert!(!path.contains('/'), "{} is not a plain file name", path);
+        }
+    }
+
+    #[test]
+    fn flattened_names_do_not_collide() {
+        let layouts = [
+            ("a", "b"),
+            ("", "a-b"),
+            ("a-", "b"),
+            ("a", "-b"),
+            ("a%2D", "b"),
+            ("2023/vacation", "img"),
+            ("2023-vacation", "img"),
+        ];
+        let names: Vec<String> = layouts
+            .iter()
+            .map(|(dir, file_name)| flattened_file_name(Path::new(dir), file_name))
+            .collect();
+        let unique: HashSet<&String> = names.iter().collect();
+        assert_eq!(unique.len(), names.len(), "{:?}", names);
+        assert_eq!(names[5], "2023-vacation-img");
+    }
+
+    #[test]
+    fn glob_patterns_translate_to_anchored_regexes() {
+        let matches =
+            |pattern: &str, path: &str| Regex::new(&glob_to_regex(pattern)).unwrap().is_match(path);
+        assert!(matches("photos/*.jpg", "photos/beach.jpg"));
+        assert!(!matches("photos/*.jpg", "photos/2023/beach.jpg"));
+        assert!(matches("photos/**/*.jpg", "photos/beach.jpg"));
+        assert!(matches("photos/**/*.jpg", "photos/2023/summer/beach.jpg"));
+        assert!(matches("photos/img?.[jp][pn]g", "photos/img1.png"));
+        assert!(!matches("photos/img[!0-9].jpg", "photos/img1.jpg"));
+        assert!(!matches("photos/*.jpg", "photos/beach.jpg.part"));
+
+        assert_eq!(glob_base("photos/**/*.jpg"), "photos");
+        assert_eq!(glob_base("/srv/photos/*.jpg"), "/srv/photos");
+        assert_eq!(glob_base("*.jpg"), ".");
     }
 }
@@ -1,31 +1,258 @@
+use std::collections::{HashMap, HashSet};
 use std::fs;
+use std::num::{NonZeroU32, NonZeroUsize};
 use std::path::Path;
+use std::process;
 use std::str;
+use std::time::Instant;
 
-use clap::Parser;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueSource};
+use image::codecs::png::CompressionType;
+use image::imageops::FilterType;
+use regex::Regex;
 
-use cutter::imageprocessing::{str_to_size, transform_images, Size};
-use cutter::s3::{download_from_s3, upload_to_s3};
-use cutter::util::get_files_in_dir;
+use cutter::analyze::{analyze, validate};
+use cutter::archive::write_zip;
+use cutter::completions::{completion_script, str_to_shell, Shell};
+use cutter::imageprocessing::{
+    dedupe_sizes, scale_size, str_to_aspect, str_to_color_count, str_to_dpi, str_to_filter,
+    str_to_flip, str_to_gravity, str_to_hash_algorithm, str_to_on_conflict, str_to_output_format,
+    str_to_png_compression, str_to_resize_mode, str_to_rotation, str_to_scale, str_to_size,
+    transform_images, AspectRatio, ContentHash, Crop, EncodeOptions, Failure, Flip, Gravity,
+    HashAlgorithm, OnConflict, OutputFormat, OutputOptions, ResizeMode, Rotation, Size,
+    TransformOptions, TransformReport, CONTENT_HASH_LENGTH,
+};
+use cutter::log;
+use cutter::manifest::{read_manifest, write_manifest};
+use cutter::preset::{str_to_preset, Preset};
+use cutter::progress::{str_to_progress_fd, Progress};
+use cutter::s3::{
+    download_from_s3, read_region_map, str_to_kms_key_id, str_to_storage, str_to_tag, upload_to_s3,
+    verify_upload, Secret, Storage, DEFAULT_CROP_KEY_PATTERN, MAX_OBJECT_TAGS,
+};
+use cutter::selftest::selftest;
+use cutter::signal::{install_interrupt_handler, interrupted, INTERRUPTED_EXIT_CODE};
+use cutter::util::{
+    available_space, check_writable, expand_glob, get_crops_in_dir, get_files_in_dir, glob_base,
+    is_dangerous_to_remove, is_glob, json_string, paths_overlap, remove_crops, remove_dir_guarded,
+    split_command, str_to_mode, str_to_size_label, SizeLabel,
+};
 
 mod cutter;
 
-extern crate clap;
-
 pub const DEFAULT_REGION: &str = "eu-central-1";
+/// Where sources fetched from a remote are downloaded without `--local-root`.
+const DEFAULT_DOWNLOAD_DIR: &str = "/tmp/cutter-sources";
 const DEFAULT_CROP_SIZES: [&str; 4] = ["200x200", "400x400", "800x800", "1920x1080"];
 
+/// Values shell completion offers for options that take one of a fixed set
+/// of names, by argument id. Parsing stays with the `str_to_*` functions, so
+/// unsupported names still get their own error message.
+const COMPLETION_VALUES: [(&str, &[&str]); 12] = [
+    ("preset", &["web", "social"]),
+    (
+        "filter",
+        &["nearest", "triangle", "catmullrom", "gaussian", "lanczos3"],
+    ),
+    ("extra-formats", &["jpg", "png"]),
+    ("png-compression", &["fast", "default", "best"]),
+    ("resize-mode", &["fill", "fit", "exact"]),
+    ("gravity", &["center", "auto"]),
+    ("rotate", &["0", "90", "180", "270"]),
+    ("flip", &["none", "horizontal", "vertical", "both"]),
+    ("output", &["text", "json"]),
+    ("hash-algorithm", &["md5", "sha256", "crc32"]),
+    ("on-conflict", &["overwrite", "skip", "rename"]),
+    ("storage", &["s3", "gcs", "local"]),
+];
+
+/// Format of the summary printed when a run completes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputMode {
+    Text,
+    Json,
+}
+
+fn str_to_output_mode(s: &str) -> Result<OutputMode, String> {
+    match s {
+        "text" => Ok(OutputMode::Text),
+        "json" => Ok(OutputMode::Json),
+        _ => Err(format!("unknown output mode '{}'", s)),
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Check decoding, cropping, directories and S3 access, then exit.
+    Selftest,
+}
+
 #[derive(Debug, Parser)]
+#[clap(subcommand_negates_reqs = true)]
 pub struct Config {
-    /// Path to files to run Cutter on.
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+    /// Print a completion script for bash, zsh or fish, then exit.
+    #[clap(long, hide = true, parse(try_from_str=str_to_shell))]
+    pub generate_completions: Option<Shell>,
+
+    /// Path to files to run Cutter on, a single image, or a glob like 'photos/**/*.jpg'.
     /// Cannot be used if files are fetched from a remote.
-    #[clap(short = 'p', long = "path", conflicts_with = "fetch-remote")]
-    pub files_path: String,
+    #[clap(
+        short = 'p',
+        long = "path",
+        required_unless_present_any = &["fetch-remote", "generate-completions", "upload-only"],
+        conflicts_with_all = &["fetch-remote", "generate-completions"]
+    )]
+    pub files_path: Option<String>,
 
-    /// Sizes to crop into. Can be used multiple times.
+    /// Sizes to crop into. Can be used multiple times, or given comma-separated
+    /// in CUTTER_SIZES.
     /// format: WIDTHxHEIGHT
-    #[clap(short='s', parse(try_from_str=str_to_size), default_values=&DEFAULT_CROP_SIZES)]
+    #[clap(
+        short = 's',
+        env = "CUTTER_SIZES",
+        use_value_delimiter = true,
+        parse(try_from_str=str_to_size),
+        default_values = &DEFAULT_CROP_SIZES
+    )]
     pub crop_sizes: Vec<Size>,
+    /// Multiply every crop size by this factor, rounding to whole pixels,
+    /// e.g. 1.5 to try everything half again as big.
+    #[clap(long, parse(try_from_str=str_to_scale))]
+    pub scale: Option<f64>,
+    /// Use a named set of crop sizes: web (responsive widths) or social (link previews).
+    /// Explicit -s and --resize-mode flags take precedence.
+    #[clap(long, parse(try_from_str=str_to_preset))]
+    pub preset: Option<Preset>,
+    /// Resampling filter used when resizing.
+    /// One of nearest, triangle, catmullrom, gaussian or lanczos3.
+    #[clap(long, parse(try_from_str=str_to_filter), default_value = "triangle")]
+    pub filter: FilterType,
+    /// Quality (1-100) for lossy output.
+    /// Defaults to a per-format preset, 82 for JPEG.
+    #[clap(long)]
+    pub quality: Option<u8>,
+    /// Also write each crop in this format, next to the JPEG. Can be used
+    /// multiple times. Supported: png.
+    #[clap(
+        long = "extra-format",
+        multiple_occurrences = true,
+        parse(try_from_str=str_to_output_format)
+    )]
+    pub extra_formats: Vec<OutputFormat>,
+    /// Stop writing crops once they add up to this many bytes, keeping the ones
+    /// already written. Useful with a small, fixed-size output disk.
+    #[clap(long)]
+    pub max_total_output_bytes: Option<u64>,
+    /// PNG compression level for PNG output: fast, default or best.
+    #[clap(long, parse(try_from_str=str_to_png_compression), default_value = "default")]
+    pub png_compression: CompressionType,
+    /// Reduce PNG output to a palette of this many colours (2-256), which
+    /// shrinks small thumbnails considerably. JPEG output is unaffected.
+    #[clap(long, parse(try_from_str=str_to_color_count))]
+    pub quantize_colors: Option<u16>,
+    /// Record this pixel density (DPI) in JPEG and PNG crops, for print and
+    /// layout tools. Pixels are unchanged; by default no density is written.
+    #[clap(long, parse(try_from_str=str_to_dpi))]
+    pub output_dpi: Option<u16>,
+    /// Remove EXIF (including GPS), XMP, IPTC and comments from every crop.
+    #[clap(long, default_value = "true", parse(try_from_str))]
+    pub strip_metadata: bool,
+    /// How images are resized into the crop size.
+    /// One of fill (crop to the exact size), fit (keep aspect ratio) or exact (stretch).
+    #[clap(long, parse(try_from_str=str_to_resize_mode), default_value = "fill")]
+    pub resize_mode: ResizeMode,
+    /// Where fill crops keep the image: center, or auto to keep its most
+    /// detailed third, falling back to the center when no part stands out.
+    #[clap(long = "crop-gravity", parse(try_from_str=str_to_gravity), default_value = "center")]
+    pub gravity: Gravity,
+    /// Swap each crop size's width and height for sources of the opposite orientation,
+    /// so e.g. 1920x1080 becomes 1080x1920 for portraits.
+    #[clap(long)]
+    pub orientation_swap: bool,
+    /// Crop to the largest box of this ratio (e.g. 16:9) that fits each source,
+    /// using each crop size's longest edge as the cap.
+    #[clap(long, parse(try_from_str=str_to_aspect))]
+    pub crop_aspect: Option<AspectRatio>,
+    /// Refuse to decode images needing more than this many bytes of memory (default 512 MiB).
+    #[clap(long)]
+    pub max_image_bytes: Option<u64>,
+    /// Refuse to decode images wider or taller than this many pixels.
+    #[clap(long)]
+    pub max_image_dimension: Option<u32>,
+    /// Spawn crops in batches of this many, finishing each batch before the
+    /// next, to bound memory use on very large galleries.
+    #[clap(long)]
+    pub batch_size: Option<NonZeroUsize>,
+    /// Number of decoded sources kept in memory while their crops at other sizes
+    /// are made. 0 decodes the source again for every size.
+    #[clap(long, default_value = "4")]
+    pub decode_cache_size: usize,
+    /// Never enlarge: for sizes larger than the source, write the source at its
+    /// own size, named after its actual dimensions.
+    #[clap(long)]
+    pub downscale_only: bool,
+    /// Crop away uniform borders, e.g. from scans or screenshots, before resizing.
+    #[clap(long)]
+    pub trim: bool,
+    /// How far (0-255) a channel may differ from the border colour and still
+    /// be trimmed.
+    #[clap(long, default_value = "10", requires = "trim")]
+    pub trim_tolerance: u8,
+    /// Skip source images whose longest edge is shorter than this many pixels.
+    #[clap(long = "min-size")]
+    pub min_source_dimension: Option<u32>,
+    /// Rotate every source clockwise by 0, 90, 180 or 270 degrees before resizing.
+    #[clap(long, parse(try_from_str=str_to_rotation), default_value = "0")]
+    pub rotate: Rotation,
+    /// Mirror every source after rotating it: none, horizontal, vertical or
+    /// both. Fixes scans that come in mirrored.
+    #[clap(long, alias = "mirror", parse(try_from_str=str_to_flip), default_value = "none")]
+    pub flip: Flip,
+    /// Keep the embedded ICC colour profile of JPEG sources in the output.
+    #[clap(long)]
+    pub preserve_icc: bool,
+
+    /// Also process files in subdirectories of the path, keeping their layout in the output.
+    #[clap(long)]
+    pub recursive: bool,
+    /// Leading directories to drop from each source's relative path when naming output,
+    /// e.g. "2023" turns "2023/vacation/img.jpg" into "vacation/img_...".
+    #[clap(long)]
+    pub strip_prefix: Option<String>,
+    /// Write all crops into one directory, prefixing names with their source directories,
+    /// e.g. "2023/vacation/img.jpg" becomes "2023-vacation-img_...". Dashes within
+    /// names are written as %2D, so "a-b.jpg" and "a/b.jpg" stay apart.
+    #[clap(long)]
+    pub flatten: bool,
+    /// How dimensions are written in crop file names: plain (200x200) or padN,
+    /// zero-padding to N digits (pad4 gives 0200x0200).
+    #[clap(long = "size-label-format", parse(try_from_str=str_to_size_label), default_value = "plain")]
+    pub size_label: SizeLabel,
+    /// Process sources with identical contents only once, symlinking the duplicates'
+    /// crops to the original's.
+    #[clap(long)]
+    pub dedupe: bool,
+    /// Copy each source next to its crops, hard-linking where possible, and
+    /// upload it with them, so the output is a self-contained gallery.
+    #[clap(long, conflicts_with = "single-output")]
+    pub include_originals: bool,
+    /// Include symlinked source files, skipping links that don't resolve to a file.
+    #[clap(long)]
+    pub follow_symlinks: bool,
+
+    /// Report the dimensions and aspect ratios of the sources, then exit without cropping.
+    #[clap(long, conflicts_with_all = &["clean", "overwrite"])]
+    pub analyze: bool,
+    /// Read the header of every source and report the unreadable, truncated
+    /// or misnamed ones, then exit without cropping.
+    #[clap(
+        long = "validate",
+        alias = "validate-images",
+        conflicts_with_all = &["clean", "overwrite", "analyze"]
+    )]
+    pub validate_sources: bool,
 
     /// Clean output directory before starting.
     #[clap(short)]
@@ -33,147 +260,1133 @@ pub struct Config {
     /// Overwrite existing files.
     #[clap(short, long)]
     pub overwrite: bool,
+    /// Don't ask before removing an output directory outside the system temp directory.
+    #[clap(short = 'y', long)]
+    pub assume_yes: bool,
     /// Tmp dir to store output files in.
     #[clap(short, long, default_value = "/tmp/cutter")]
     pub tmp_dir: String,
+    /// Number of worker threads for the async runtime.
+    /// Defaults to the number of CPU cores.
+    #[clap(long)]
+    pub workers: Option<NonZeroUsize>,
     /// Enable verbose output.
     #[clap(short, long)]
     pub verbose: bool,
+    /// Print progress every this many operations, instead of every quarter
+    /// of them (at most every 25).
+    #[clap(long)]
+    pub progress_every: Option<NonZeroU32>,
+    /// Write machine-readable progress lines ({"done":N,"total":M}) to this file descriptor.
+    #[clap(long, parse(try_from_str=str_to_progress_fd))]
+    pub progress_fd: Option<i32>,
+    /// Print a summary of the run as text or as a single line of JSON.
+    #[clap(long, parse(try_from_str=str_to_output_mode), default_value = "text")]
+    pub output: OutputMode,
+    /// Include a short hash of the output in crop file names.
+    #[clap(long)]
+    pub content_hash: bool,
+    /// Digest for --content-hash: md5, sha256 or crc32.
+    #[clap(
+        long,
+        parse(try_from_str=str_to_hash_algorithm),
+        default_value = "md5",
+        requires = "content-hash"
+    )]
+    pub hash_algorithm: HashAlgorithm,
+    /// Number of hex digits of the digest kept in file names, 8 by default.
+    #[clap(long, requires = "content-hash")]
+    pub hash_length: Option<usize>,
+    /// Command to run on every crop after it is written, e.g. "jpegoptim {}".
+    /// Every `{}` is replaced by the crop's path, or the path is appended.
+    /// No shell is involved: arguments are split on whitespace, and quotes
+    /// or backslashes keep spaces in one argument.
+    #[clap(long)]
+    pub post_process_cmd: Option<String>,
+    /// Write the crop to exactly this path instead of a generated name.
+    /// Only valid when cropping a single file to a single size.
+    #[clap(long = "out", conflicts_with = "extra-formats")]
+    pub single_output: Option<String>,
+    /// What to do when a crop's file already exists locally: overwrite, skip
+    /// or rename (write it with a -1, -2, ... suffix).
+    #[clap(long, parse(try_from_str=str_to_on_conflict), default_value = "overwrite")]
+    pub on_conflict: OnConflict,
+    /// Octal permissions for written crops, e.g. 0644 (unix only).
+    #[clap(long, parse(try_from_str=str_to_mode))]
+    pub file_mode: Option<u32>,
+    /// Octal permissions for created output directories, e.g. 0755 (unix only).
+    #[clap(long, parse(try_from_str=str_to_mode))]
+    pub dir_mode: Option<u32>,
+    /// Write a JSON manifest of the created crops to this path.
+    #[clap(long)]
+    pub manifest: Option<String>,
+    /// Also bundle every crop into a single ZIP archive at this path.
+    #[clap(long)]
+    pub zip: Option<String>,
+    /// Keep crops smaller than this many bytes as base64 data URIs in the manifest
+    /// instead of writing or uploading them.
+    #[clap(long = "inline-threshold", requires = "manifest")]
+    pub inline_threshold_bytes: Option<u64>,
+    /// Resume an interrupted run, skipping crops listed in the existing manifest.
+    #[clap(long, requires = "manifest", conflicts_with_all = &["clean", "overwrite"])]
+    pub resume: bool,
+    /// Skip sources whose path below the source directory sorts before this,
+    /// e.g. img3000.jpg, to re-run the tail of a run without a manifest.
+    #[clap(long)]
+    pub continue_from: Option<String>,
+
+    /// After uploading, list the bucket and check every crop is there with
+    /// the local size and checksum.
+    #[clap(long, requires = "s3-bucket-name")]
+    pub verify: bool,
 
+    /// Upload the crops already in the output directory to S3, without
+    /// cropping anything.
+    #[clap(long, requires = "s3-bucket-name", conflicts_with_all = &["clean", "overwrite", "analyze", "validate-sources"])]
+    pub upload_only: bool,
+
+    /// Download the source images from S3, without cropping or uploading them.
+    #[clap(long, requires = "s3-bucket-name", conflicts_with_all = &["upload-only", "analyze", "validate-sources"])]
+    pub download_only: bool,
+
+    /// Service the bucket is in: s3, gcs for Google Cloud Storage through its
+    /// S3-compatible XML API, with an HMAC key given as the AWS credentials, or
+    /// local for a directory named by -b that mirrors the bucket.
+    #[clap(long, parse(try_from_str=str_to_storage), default_value = "s3")]
+    pub storage: Storage,
     /// Name of S3 bucket to upload files to.
-    #[clap(short = 'b')]
+    #[clap(short = 'b', env = "CUTTER_S3_BUCKET")]
     pub s3_bucket_name: Option<String>,
     /// Region of S3 bucket.
-    #[clap(long)]
+    #[clap(long, env = "CUTTER_REGION")]
     pub s3_region: Option<String>,
-    /// Prefix for files uploaded to S3.
+    /// File mapping bucket names to regions, one "bucket = region" per line.
+    /// Used for buckets without an explicit --s3-region.
+    #[clap(long = "region-map", parse(try_from_str=read_region_map))]
+    pub region_map: Option<HashMap<String, String>>,
+    /// AWS access key id, instead of the one found in the environment.
+    #[clap(long, requires = "aws-secret-access-key")]
+    pub aws_access_key_id: Option<String>,
+    /// AWS secret access key belonging to --aws-access-key-id.
+    #[clap(long, requires = "aws-access-key-id")]
+    pub aws_secret_access_key: Option<Secret>,
+    /// Session token of temporary credentials, e.g. from an assumed role.
+    #[clap(long, requires = "aws-access-key-id")]
+    pub aws_session_token: Option<Secret>,
+    /// Prefix for files in S3. Can be used multiple times, in which case
+    /// each prefix is downloaded and processed into a directory of its own.
+    #[clap(long = "s3-prefix", multiple_occurrences = true)]
+    pub s3_prefixes: Vec<String>,
+    /// Tag uploaded crops with key=value pairs, e.g. type=thumbnail,gallery=2023.
+    /// Can be used multiple times.
+    #[clap(
+        long = "object-tag",
+        multiple_occurrences = true,
+        use_value_delimiter = true,
+        parse(try_from_str=str_to_tag)
+    )]
+    pub object_tags: Vec<(String, String)>,
+    /// Encrypt uploaded crops with SSE-KMS using this key: a key ID, key ARN
+    /// or alias. By default the bucket's default encryption applies.
+    #[clap(long, parse(try_from_str=str_to_kms_key_id))]
+    pub sse_kms_key_id: Option<String>,
+    /// Prefix to upload crops under, if different from the source prefix.
     #[clap(long)]
-    pub s3_prefix: Option<String>,
+    pub s3_output_prefix: Option<String>,
+    /// Directory to download S3 objects into, laid out exactly like their keys,
+    /// instead of deriving local paths from the source path and prefix.
+    #[clap(long, requires = "fetch-remote")]
+    pub local_root: Option<String>,
+    /// File of object keys to download, one per line, instead of listing the
+    /// bucket. Useful when a pipeline already knows which objects changed.
+    #[clap(long, requires = "fetch-remote")]
+    pub s3_keys_file: Option<String>,
     /// Fetch files from S3 bucket for Cutting.
     #[clap(short = 'r', long)]
     pub fetch_remote: Option<bool>,
+    /// Regex matching object keys of generated crops, which are not downloaded.
+    #[clap(long, default_value = DEFAULT_CROP_KEY_PATTERN)]
+    pub crop_key_pattern: Regex,
+    /// Most objects downloaded from S3 in parallel; starts lower and ramps up
+    /// unless S3 throttles requests.
+    #[clap(long, default_value = "4")]
+    pub download_concurrency: NonZeroUsize,
+    /// Most files uploaded to S3 in parallel; starts lower and ramps up unless
+    /// S3 throttles requests.
+    #[clap(long, default_value = "4")]
+    pub upload_concurrency: NonZeroUsize,
+    /// Skip S3 objects that fail to download or upload instead of aborting,
+    /// exiting non-zero at the end if any did.
+    #[clap(long = "continue-on-s3-error")]
+    pub continue_on_error: bool,
+    /// Resume interrupted downloads from their partial file using range requests.
+    #[clap(long)]
+    pub resume_downloads: bool,
 }
 
-#[tokio::main]
-pub async fn main() {
-    let config = Config::parse();
-    run(config).await;
+impl Config {
+    /// Region of `bucket` from `--s3-region` or the region map, if known.
+    pub fn region_for(&self, bucket: &str) -> Option<String> {
+        self.s3_region.to_owned().or_else(|| {
+            self.region_map
+                .as_ref()
+                .and_then(|region_map| region_map.get(bucket).cloned())
+        })
+    }
+
+    /// Fills in the preset's settings for anything not given on the command line
+    /// or in the environment.
+    pub fn apply_preset(&mut self, matches: &ArgMatches) {
+        let preset = match self.preset {
+            Some(preset) => preset,
+            None => return,
+        };
+        let defaulted = |id| {
+            !matches!(
+                matches.value_source(id),
+                Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+            )
+        };
+        if defaulted("crop-sizes") {
+            self.crop_sizes = preset.crop_sizes();
+        }
+        if defaulted("resize-mode") {
+            self.resize_mode = preset.resize_mode();
+        }
+    }
+
+    /// Multiplies the crop sizes by `--scale`, keeping them at least a pixel.
+    pub fn scale_crop_sizes(&mut self) -> Result<(), String> {
+        if let Some(factor) = self.scale {
+            for size in &mut self.crop_sizes {
+                *size = scale_size(*size, factor)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sorts the crop sizes and drops repeated ones, returning the sizes that
+    /// were given more than once.
+    pub fn dedupe_crop_sizes(&mut self) -> Vec<Size> {
+        dedupe_sizes(&mut self.crop_sizes)
+    }
+
+    /// Checks combinations of options clap can't express.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.crop_sizes.is_empty() {
+            return Err("no crop sizes given, pass at least one -s WIDTHxHEIGHT".to_string());
+        }
+        if let Some(length) = self.hash_length {
+            let max = self.hash_algorithm.hex_len();
+            if !(1..=max).contains(&length) {
+                return Err(format!(
+                    "--hash-length must be between 1 and {} for {:?}",
+                    max, self.hash_algorithm
+                ));
+            }
+        }
+        if self.storage == Storage::Gcs && !self.object_tags.is_empty() {
+            return Err("GCS's XML API doesn't support object tags".to_string());
+        }
+        if self.storage == Storage::Gcs && self.sse_kms_key_id.is_some() {
+            return Err("GCS's XML API doesn't support SSE-KMS".to_string());
+        }
+        if self.object_tags.len() > MAX_OBJECT_TAGS {
+            return Err(format!(
+                "S3 allows at most {} tags per object, got {}",
+                MAX_OBJECT_TAGS,
+                self.object_tags.len()
+            ));
+        }
+        if let Some(cmd) = &self.post_process_cmd {
+            split_command(cmd)?;
+        }
+        if let Some(out) = &self.single_output {
+            if self.crop_sizes.len() != 1 || !Path::new(self.source_dir()).is_file() {
+                return Err(format!(
+                    "--out {} needs a single source file and a single crop size",
+                    out
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Directory sources are read from: `--local-root` when downloading
+    /// there, the source path otherwise, or a default download directory.
+    pub fn source_dir(&self) -> &str {
+        self.local_root
+            .as_deref()
+            .or(self.files_path.as_deref())
+            .unwrap_or(DEFAULT_DOWNLOAD_DIR)
+    }
+
+    /// Whether S3 prefixes each map to a directory of their own.
+    pub fn namespaced_prefixes(&self) -> bool {
+        self.s3_prefixes.len() > 1
+    }
+
+    pub fn transform_options(&self) -> TransformOptions {
+        TransformOptions {
+            width: 0,
+            height: 0,
+            filter: self.filter,
+            resize_mode: self.resize_mode,
+            rotate: self.rotate,
+            flip: self.flip,
+            preserve_icc: self.preserve_icc,
+            min_source_dimension: self.min_source_dimension,
+            crop_aspect: self.crop_aspect,
+            orientation_swap: self.orientation_swap,
+            max_image_bytes: self.max_image_bytes,
+            max_image_dimension: self.max_image_dimension,
+            decode_cache_size: self.decode_cache_size,
+            trim: if self.trim {
+                Some(self.trim_tolerance)
+            } else {
+                None
+            },
+            downscale_only: self.downscale_only,
+            gravity: self.gravity,
+            batch_size: self.batch_size,
+            scale: self.scale,
+        }
+    }
+
+    pub fn output_options(&self) -> OutputOptions {
+        OutputOptions {
+            path: self.tmp_dir.to_owned(),
+            source_root: if is_glob(self.source_dir()) {
+                glob_base(self.source_dir())
+            } else {
+                self.source_dir().to_owned()
+            },
+            strip_prefix: self.strip_prefix.to_owned(),
+            flatten: self.flatten,
+            size_label: self.size_label,
+            dedupe: self.dedupe,
+            include_originals: self.include_originals,
+            content_hash: if self.content_hash {
+                Some(ContentHash {
+                    algorithm: self.hash_algorithm,
+                    length: self.hash_length.unwrap_or(CONTENT_HASH_LENGTH),
+                })
+            } else {
+                None
+            },
+            post_process_cmd: self.post_process_cmd.to_owned(),
+            inline_threshold: self.inline_threshold_bytes,
+            single_output: self.single_output.to_owned(),
+            file_mode: self.file_mode,
+            dir_mode: self.dir_mode,
+            extra_formats: self.extra_formats.to_owned(),
+            max_total_bytes: self.max_total_output_bytes,
+            on_conflict: self.on_conflict,
+        }
+    }
+
+    pub fn encode_options(&self) -> EncodeOptions {
+        EncodeOptions {
+            quality: self.quality,
+            png_compression: self.png_compression,
+            strip_metadata: self.strip_metadata,
+            quantize_colors: self.quantize_colors,
+            dpi: self.output_dpi,
+            ..EncodeOptions::default()
+        }
+    }
+}
+
+pub fn main() {
+    let matches = Config::command().get_matches();
+    if let Some(shell) = matches.value_of("generate-completions") {
+        let shell = str_to_shell(shell).expect("validated by clap");
+        print!(
+            "{}",
+            completion_script(&Config::command(), shell, &COMPLETION_VALUES)
+        );
+        return;
+    }
+    let mut config = Config::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    if config.output == OutputMode::Json {
+        log::to_stderr();
+    }
+    config.apply_preset(&matches);
+    if let Err(err) = config.scale_crop_sizes() {
+        log::error(&err);
+        process::exit(1);
+    }
+    for size in config.dedupe_crop_sizes() {
+        log::warn(&format!(
+            "{}x{} was given more than once, cropping it once",
+            size.0, size.1
+        ));
+    }
+
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+    if let Some(workers) = config.workers {
+        runtime.worker_threads(workers.get());
+    }
+    let runtime = runtime.build().expect("failed to build tokio runtime");
+
+    if let Some(Command::Selftest) = config.command {
+        if !runtime.block_on(selftest(&config)) {
+            process::exit(1);
+        }
+        return;
+    }
+    runtime.block_on(run(config));
 }
 
 pub async fn run(config: Config) {
-    println!("Executing with config: {:?}", config);
+    let started = Instant::now();
+    log::info(&format!("Executing with config: {:?}", config));
 
     if config.verbose {
         explain_config(&config);
     }
 
+    if let Err(err) = config.validate() {
+        log::error(&err);
+        process::exit(1);
+    }
+
+    install_interrupt_handler();
+
+    if config.upload_only {
+        let crops = get_crops_in_dir(&config.tmp_dir, &config.crop_key_pattern);
+        log::info(&format!(
+            "Found {} crops in {}",
+            crops.len(),
+            config.tmp_dir
+        ));
+        let failures = upload_and_verify(&config, crops).await;
+        if interrupted() {
+            process::exit(INTERRUPTED_EXIT_CODE);
+        }
+        if !failures.is_empty() {
+            log::error("Some S3 objects failed to transfer");
+            process::exit(1);
+        }
+        return;
+    }
+
+    if config.download_only {
+        let failures = download_from_s3(&config).await;
+        if interrupted() {
+            process::exit(INTERRUPTED_EXIT_CODE);
+        }
+        if !failures.is_empty() {
+            log::error("Some S3 objects failed to transfer");
+            process::exit(1);
+        }
+        return;
+    }
+
     if Path::new(&config.tmp_dir).exists() && (config.clean || config.overwrite) {
-        fs::remove_dir_all(&config.tmp_dir).unwrap();
+        if paths_overlap(&config.tmp_dir, config.source_dir())
+            && !is_dangerous_to_remove(&config.tmp_dir)
+        {
+            // Writing crops next to the sources: never wipe the originals.
+            log::warn(&format!(
+                "{} overlaps the source directory, only removing previous crops",
+                config.tmp_dir
+            ));
+            let removed = remove_crops(&config.tmp_dir, &config.crop_key_pattern)
+                .expect("failed to remove previous crops");
+            log::info(&format!("Removed {} previous crops", removed));
+        } else if let Err(err) = remove_dir_guarded(&config.tmp_dir, config.assume_yes) {
+            log::error(&err);
+            process::exit(1);
+        }
     }
 
-    if !Path::new(&config.tmp_dir).exists() {
-        fs::create_dir(&config.tmp_dir).unwrap();
+    let writable = if Path::new(&config.tmp_dir).exists() {
+        check_writable(&config.tmp_dir)
+    } else {
+        fs::create_dir(&config.tmp_dir).and_then(|_| check_writable(&config.tmp_dir))
+    };
+    if let Err(err) = writable {
+        log::error(&format!("cannot write to {}: {}", config.tmp_dir, err));
+        process::exit(1);
     }
 
+    let mut s3_failures = Vec::new();
     if let Some(fetch_remote) = config.fetch_remote {
         if config.s3_bucket_name.is_none() {
             panic!("shouldnt happen because config cheks for this :)");
         }
         if fetch_remote {
-            if let Some(s3_bucket_name) = &config.s3_bucket_name {
-                download_from_s3(
-                    s3_bucket_name,
-                    &config
-                        .s3_region
-                        .to_owned()
-                        .unwrap_or_else(|| DEFAULT_REGION.to_string()),
-                    &config
-                        .s3_prefix
-                        .to_owned()
-                        .unwrap_or_else(|| "".to_string()),
-                    &config.files_path,
-                    config.overwrite,
-                    config.clean,
-                    config.verbose,
-                )
-                .await;
+            s3_failures.extend(download_from_s3(&config).await);
+            if interrupted() {
+                process::exit(INTERRUPTED_EXIT_CODE);
             }
         }
     }
 
-    println!("Finding files in {}", &config.files_path);
-    let files = get_files_in_dir(config.files_path);
+    let source_dir = config.source_dir();
+    let mut files = if is_glob(source_dir) {
+        log::info(&format!("Finding files matching {}", source_dir));
+        expand_glob(source_dir).unwrap_or_else(|err| {
+            log::error(&err);
+            process::exit(1);
+        })
+    } else if Path::new(source_dir).is_file() {
+        vec![source_dir.to_owned()]
+    } else {
+        log::info(&format!("Finding files in {}", source_dir));
+        let fetched = config.fetch_remote == Some(true);
+        get_files_in_dir(
+            source_dir.to_owned(),
+            config.follow_symlinks,
+            config.recursive
+                || (fetched && (config.namespaced_prefixes() || config.local_root.is_some())),
+            &config.crop_key_pattern,
+        )
+    };
 
-    let processed_files = transform_images(
+    files.sort();
+    if let Some(continue_from) = &config.continue_from {
+        let root = if is_glob(source_dir) {
+            glob_base(source_dir)
+        } else {
+            source_dir.to_owned()
+        };
+        let before = files.len();
+        files.retain(|file| {
+            let relative = Path::new(file)
+                .strip_prefix(&root)
+                .unwrap_or(Path::new(file));
+            relative.to_string_lossy().as_ref() >= continue_from.as_str()
+        });
+        log::info(&format!(
+            "Continuing from {}, skipping {} earlier files",
+            continue_from,
+            before - files.len()
+        ));
+    }
+
+    if config.analyze {
+        analyze(&files);
+        return;
+    }
+
+    if config.validate_sources {
+        let failures = validate(&files);
+        log::info(&format!(
+            "Validated {} files, {} unreadable",
+            files.len(),
+            failures.len()
+        ));
+        if !failures.is_empty() {
+            process::exit(1);
+        }
+        return;
+    }
+
+    let estimate = estimate_output_bytes(files.len(), &config.crop_sizes);
+    if let Some(available) = available_space(&config.tmp_dir) {
+        if available < estimate {
+            log::warn(&format!(
+                "{} may not have enough free space: {} MB available, roughly {} MB needed",
+                config.tmp_dir,
+                available / 1_000_000,
+                estimate / 1_000_000
+            ));
+        }
+    }
+
+    let completed = if config.resume {
+        resumable_crops(config.manifest.as_deref().unwrap_or_default())
+    } else {
+        Vec::new()
+    };
+
+    let mut report = transform_images(
         files,
-        config.tmp_dir.to_owned(),
         &config.crop_sizes,
-        config.verbose,
+        &config.transform_options(),
+        &config.encode_options(),
+        &config.output_options(),
+        completed,
+        &Progress::new(config.verbose, config.progress_every, config.progress_fd),
     )
     .await;
 
-    if let Some(s3_bucket_name) = config.s3_bucket_name {
-        upload_to_s3(
-            &s3_bucket_name,
-            &config
-                .s3_region
-                .unwrap_or_else(|| DEFAULT_REGION.to_string()),
-            &config.s3_prefix.unwrap_or_else(|| "".to_string()),
-            &config.tmp_dir,
-            processed_files,
-            config.verbose,
-        )
-        .await;
+    if let Some(manifest) = &config.manifest {
+        log::info(&format!("Writing manifest to {}", manifest));
+        if let Err(err) = write_manifest(manifest, &report.crops) {
+            log::error(&format!("failed to write {}: {}", manifest, err));
+            process::exit(1);
+        }
+    }
+
+    if let Some(zip) = &config.zip {
+        log::info(&format!("Writing {} crops to {}", report.crops.len(), zip));
+        if let Err(err) = write_zip(zip, &config.tmp_dir, &report.crops) {
+            log::error(&format!("failed to write {}: {}", zip, err));
+        }
+    }
+
+    if report.over_budget > 0 {
+        log::warn(&format!(
+            "Output budget of {} bytes reached, {} of {} crops not made",
+            config.max_total_output_bytes.unwrap_or_default(),
+            report.over_budget,
+            report.operations
+        ));
     }
 
-    println!("Done!");
+    if report.interrupted {
+        log::warn(&format!(
+            "Interrupted after {} of {} crops",
+            report.crops.len(),
+            report.operations
+        ));
+        process::exit(INTERRUPTED_EXIT_CODE);
+    }
+
+    let processed_files = report
+        .crops
+        .iter()
+        .filter(|crop| crop.data_uri.is_none())
+        .map(|crop| crop.path.to_owned())
+        .chain(report.originals.iter().cloned())
+        .collect();
+
+    if config.s3_bucket_name.is_some() {
+        s3_failures.extend(upload_and_verify(&config, processed_files).await);
+        if interrupted() {
+            process::exit(INTERRUPTED_EXIT_CODE);
+        }
+    }
+
+    let s3_failed = !s3_failures.is_empty();
+    report.failures.extend(s3_failures);
+
+    match config.output {
+        OutputMode::Text => {
+            for (size, count) in undersized_sizes(&report) {
+                log::warn(&format!(
+                    "{}x{} was larger than {} source(s), which were {}",
+                    size.0,
+                    size.1,
+                    count,
+                    if config.downscale_only {
+                        "kept at their own size"
+                    } else {
+                        "upscaled"
+                    }
+                ));
+            }
+            log::info("Done!")
+        }
+        OutputMode::Json => {
+            println!("{}", json_summary(&report, started.elapsed().as_secs_f64()))
+        }
+    }
+
+    if s3_failed {
+        log::error("Some S3 objects failed to transfer");
+        process::exit(1);
+    }
+}
+
+/// Uploads `files`, then checks they all landed when `--verify` is set.
+async fn upload_and_verify(config: &Config, files: Vec<String>) -> Vec<Failure> {
+    let mut failures = upload_to_s3(config, files.to_owned()).await;
+    if config.verify && !interrupted() {
+        let failed: HashSet<&str> = failures.iter().map(|f| f.source.as_str()).collect();
+        let uploaded: Vec<String> = files
+            .into_iter()
+            .filter(|file| !failed.contains(file.as_str()))
+            .collect();
+        failures.extend(verify_upload(config, &uploaded).await);
+    }
+    failures
+}
+
+/// Rough size of the crops produced for `numfiles` sources, assuming JPEG
+/// compresses 24-bit pixels about tenfold.
+fn estimate_output_bytes(numfiles: usize, sizes: &[Size]) -> u64 {
+    let bytes_per_file: u64 = sizes
+        .iter()
+        .map(|size| u64::from(size.0) * u64::from(size.1) * 3 / 10)
+        .sum();
+    bytes_per_file * numfiles as u64
+}
+
+/// Crops listed in the manifest at `path` whose files still exist.
+fn resumable_crops(path: &str) -> Vec<Crop> {
+    if !Path::new(path).exists() {
+        log::info(&format!("No manifest at {}, starting from scratch", path));
+        return Vec::new();
+    }
+    let crops = match read_manifest(path) {
+        Ok(crops) => crops,
+        Err(err) => {
+            // Most likely cut short by a crash; its crops are overwritten.
+            log::error(&format!("cannot resume from {}: {}", path, err));
+            log::warn("Starting from scratch");
+            return Vec::new();
+        }
+    };
+    let (existing, missing): (Vec<Crop>, Vec<Crop>) = crops
+        .into_iter()
+        .partition(|crop| crop.data_uri.is_some() || Path::new(&crop.path).is_file());
+    if !missing.is_empty() {
+        log::warn(&format!(
+            "{} crops in the manifest no longer exist and will be redone",
+            missing.len()
+        ));
+    }
+    existing
+}
+
+/// Sizes that were larger than some sources, most often first.
+fn undersized_sizes(report: &TransformReport) -> Vec<(Size, usize)> {
+    let mut sizes: Vec<(Size, usize)> = report.undersized.iter().map(|(s, n)| (*s, *n)).collect();
+    sizes.sort_by_key(|(size, count)| (std::cmp::Reverse(*count), size.0, size.1));
+    sizes
+}
+
+fn json_summary(report: &TransformReport, elapsed_seconds: f64) -> String {
+    let created: Vec<String> = report
+        .crops
+        .iter()
+        .filter(|crop| crop.data_uri.is_none())
+        .map(|crop| json_string(&crop.path))
+        .collect();
+    let failures: Vec<String> = report
+        .failures
+        .iter()
+        .map(|failure| {
+            let size = failure
+                .size
+                .map(|size| format!(",\"size\":\"{}x{}\"", size.0, size.1))
+                .unwrap_or_default();
+            format!(
+                "{{\"file\":{}{},\"reason\":{}}}",
+                json_string(&failure.source),
+                size,
+                json_string(&failure.reason)
+            )
+        })
+        .collect();
+    let undersized: Vec<String> = undersized_sizes(report)
+        .iter()
+        .map(|(size, count)| format!("\"{}x{}\":{}", size.0, size.1, count))
+        .collect();
+    format!(
+        "{{\"processed\":{},\"created\":[{}],\"failures\":[{}],\"undersized\":{{{}}},\"elapsed_seconds\":{:.3}}}",
+        report.operations,
+        created.join(","),
+        failures.join(","),
+        undersized.join(","),
+        elapsed_seconds
+    )
 }
 
 fn explain_config(config: &Config) {
-    println!("Explaining configuration: {:?}", config);
+    log::plain(&format!("Explaining configuration: {:?}", config));
 
-    println!("*************** CONFIGURATION ***************");
+    log::plain("*************** CONFIGURATION ***************");
 
     if let Some(s3_bucket_name) = &config.s3_bucket_name {
-        println!(
+        log::plain(&format!(
             "Will publish files to S3 bucket '{}' after completion",
             s3_bucket_name
-        );
+        ));
 
-        println!("Will overwrite files on remote: {}", config.overwrite);
+        log::plain(&format!(
+            "Will overwrite files on remote: {}",
+            config.overwrite
+        ));
     }
 
     if let Some(fetch_remote) = config.fetch_remote {
         if fetch_remote {
-            println!(
-                "Fetching files from remote: {}/{}",
+            log::plain(&format!(
+                "Fetching files from remote: {} ({})",
                 config
                     .s3_bucket_name
                     .as_ref()
                     .expect("need s3 bucket name if going to fetch from remote"),
-                config.s3_prefix.as_ref().unwrap_or(&"".to_string())
-            );
+                config.s3_prefixes.join(", ")
+            ));
         }
     } else {
-        println!(
+        log::plain(&format!(
             "Path to source files locally on this host: {}",
-            config.files_path
-        );
+            config.source_dir()
+        ));
     }
 
-    println!("Working/temporary directory: {}", config.tmp_dir);
+    log::plain(&format!("Working/temporary directory: {}", config.tmp_dir));
+
+    if config.content_hash {
+        log::plain(&format!(
+            "Will include {:?} content hashes in output file names",
+            config.hash_algorithm
+        ));
+    }
+
+    if let Some(manifest) = &config.manifest {
+        log::plain(&format!("Will write manifest to {}", manifest));
+    }
+
+    if let Some(zip) = &config.zip {
+        log::plain(&format!("Will bundle crops into {}", zip));
+    }
 
     if config.clean {
-        println!("Will clean working directory before starting");
+        log::plain("Will clean working directory before starting");
     }
 
-    println!(
+    log::plain(&format!(
         "Will crop to the following {} size(s):",
         config.crop_sizes.len()
-    );
+    ));
     for size in &config.crop_sizes {
-        println!("\t{:?}", size);
+        log::plain(&format!("\t{:?}", size));
+    }
+
+    log::plain("*************** END CONFIGURATION ***************");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Held while parsing, as tests that set `CUTTER_*` variables would
+    /// otherwise leak them into each other.
+    static ENV: Mutex<()> = Mutex::new(());
+
+    /// Parses `args` like `Config::parse_from`, without racing tests that
+    /// set environment variables.
+    pub(crate) fn parse<'a>(args: impl IntoIterator<Item = &'a str>) -> Config {
+        let _env = ENV.lock().unwrap_or_else(|err| err.into_inner());
+        Config::parse_from(args)
+    }
+
+    #[test]
+    fn completion_values_are_accepted() {
+        let cmd = Config::command();
+        for (id, values) in COMPLETION_VALUES {
+            let arg = cmd
+                .get_arguments()
+                .find(|arg| arg.get_id() == id)
+                .unwrap_or_else(|| panic!("no argument {}", id));
+            let flag = format!("--{}", arg.get_long().unwrap());
+            for value in values {
+                let _env = ENV.lock().unwrap_or_else(|err| err.into_inner());
+                let args = [
+                    "cutter",
+                    "-p",
+                    "/tmp/cutter-src",
+                    "--content-hash",
+                    &flag,
+                    value,
+                ];
+                let parsed = Config::try_parse_from(args);
+                assert!(parsed.is_ok(), "{} {}", flag, value);
+            }
+        }
+    }
+
+    #[test]
+    fn empty_crop_sizes_are_rejected() {
+        let mut config = parse(["cutter", "-p", "/tmp/cutter-src"]);
+        assert_eq!(config.validate(), Ok(()));
+
+        config.crop_sizes.clear();
+        let err = config.validate().unwrap_err();
+        assert!(err.contains("no crop sizes"), "{}", err);
     }
 
-    println!("*************** END CONFIGURATION ***************");
+    #[test]
+    fn repeated_crop_sizes_are_dropped() {
+        let mut config = parse([
+            "cutter",
+            "-p",
+            "/tmp/cutter-src",
+            "-s",
+            "400x400",
+            "-s",
+            "200x200",
+            "-s",
+            "400x400",
+        ]);
+        assert_eq!(config.dedupe_crop_sizes(), [Size(400, 400)]);
+        assert_eq!(config.crop_sizes, [Size(200, 200), Size(400, 400)]);
+    }
+
+    #[test]
+    fn crop_sizes_are_read_from_the_environment() {
+        let _env = ENV.lock().unwrap_or_else(|err| err.into_inner());
+        std::env::set_var("CUTTER_SIZES", "200x200, 400x300 ");
+        let parsed = Config::try_parse_from(["cutter", "-p", "/tmp/cutter-src"]);
+        std::env::set_var("CUTTER_SIZES", "200x200,400");
+        let malformed = Config::try_parse_from(["cutter", "-p", "/tmp/cutter-src"]);
+        std::env::remove_var("CUTTER_SIZES");
+
+        assert_eq!(parsed.unwrap().crop_sizes, [Size(200, 200), Size(400, 300)]);
+        assert!(malformed.is_err());
+    }
+
+    #[tokio::test]
+    async fn resume_makes_missing_formats() {
+        let out = std::env::temp_dir().join(format!("cutter-test-resume-{}", process::id()));
+        let _ = fs::remove_dir_all(&out);
+        let out = out.to_string_lossy().into_owned();
+        let source = format!(
+            "{}/tests/fixtures/landscape.jpg",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let config = parse([
+            "cutter",
+            "-p",
+            &source,
+            "-t",
+            &out,
+            "-s",
+            "20x20",
+            "--extra-format",
+            "png",
+        ]);
+        let (transform, encode, output) = (
+            config.transform_options(),
+            config.encode_options(),
+            config.output_options(),
+        );
+        let progress = Progress::new(false, None, None);
+        let run = |completed| {
+            transform_images(
+                vec![source.to_owned()],
+                &config.crop_sizes,
+                &transform,
+                &encode,
+                &output,
+                completed,
+                &progress,
+            )
+        };
+
+        let first = run(Vec::new()).await;
+        let (png, jpeg): (Vec<Crop>, Vec<Crop>) = first
+            .crops
+            .into_iter()
+            .partition(|crop| crop.format == OutputFormat::Png);
+        fs::remove_file(&png[0].path).unwrap();
+
+        let resumed = run(jpeg).await;
+        assert_eq!(resumed.operations, 1);
+        let formats: Vec<OutputFormat> = resumed.crops.iter().map(|crop| crop.format).collect();
+        assert_eq!(formats, [OutputFormat::Jpeg, OutputFormat::Png]);
+        assert!(Path::new(&png[0].path).is_file());
+    }
+
+    #[tokio::test]
+    async fn crops_over_budget_leave_no_files() {
+        let dir = std::env::temp_dir().join(format!("cutter-test-budget-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let source = format!(
+            "{}/tests/fixtures/landscape.jpg",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let run = |out: &str, budget: Option<u64>| {
+            let config = parse([
+                "cutter",
+                "-p",
+                &source,
+                "-t",
+                out,
+                "-s",
+                "20x20",
+                "--extra-format",
+                "png",
+            ]);
+            let (transform, encode, mut output) = (
+                config.transform_options(),
+                config.encode_options(),
+                config.output_options(),
+            );
+            output.max_total_bytes = budget;
+            let sources = vec![source.to_owned()];
+            async move {
+                transform_images(
+                    sources,
+                    &config.crop_sizes,
+                    &transform,
+                    &encode,
+                    &output,
+                    Vec::new(),
+                    &Progress::new(false, None, None),
+                )
+                .await
+            }
+        };
+
+        let unlimited = run(&dir.join("all").to_string_lossy(), None).await;
+        let total: u64 = unlimited
+            .crops
+            .iter()
+            .map(|crop| fs::metadata(&crop.path).unwrap().len())
+            .sum();
+        assert_eq!(unlimited.crops.len(), 2);
+
+        // Room for the JPEG but not the PNG as well: neither is written.
+        let out = dir.join("over");
+        let report = run(&out.to_string_lossy(), Some(total - 1)).await;
+        assert_eq!(report.over_budget, 1);
+        assert!(report.crops.is_empty());
+        let written = fs::read_dir(&out).map_or(0, |entries| entries.count());
+        assert_eq!(written, 0);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn skipped_crops_record_the_existing_file() {
+        let out = std::env::temp_dir().join(format!("cutter-test-skip-{}", process::id()));
+        let _ = fs::remove_dir_all(&out);
+        let out = out.to_string_lossy().into_owned();
+        let source = format!(
+            "{}/tests/fixtures/landscape.jpg",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let config = parse([
+            "cutter",
+            "-p",
+            &source,
+            "-t",
+            &out,
+            "-s",
+            "20x20",
+            "--on-conflict",
+            "skip",
+        ]);
+        let (transform, encode, output) = (
+            config.transform_options(),
+            config.encode_options(),
+            config.output_options(),
+        );
+        let progress = Progress::new(false, None, None);
+        let run = || {
+            transform_images(
+                vec![source.to_owned()],
+                &config.crop_sizes,
+                &transform,
+                &encode,
+                &output,
+                Vec::new(),
+                &progress,
+            )
+        };
+
+        let first = run().await;
+        let path = first.crops[0].path.to_owned();
+        image::DynamicImage::new_rgb8(10, 5).save(&path).unwrap();
+
+        let second = run().await;
+        assert_eq!(second.crops[0].path, path);
+        assert_eq!((second.crops[0].width, second.crops[0].height), (10, 5));
+        let _ = fs::remove_dir_all(&out);
+    }
+
+    #[tokio::test]
+    async fn sources_with_equal_stems_get_their_own_crops() {
+        let dir = std::env::temp_dir().join(format!("cutter-test-stems-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("in")).unwrap();
+        let fixture = format!(
+            "{}/tests/fixtures/landscape.jpg",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let sources: Vec<String> = ["photo.jpg", "photo.png"]
+            .iter()
+            .map(|name| dir.join("in").join(name).to_string_lossy().into_owned())
+            .collect();
+        fs::copy(&fixture, &sources[0]).unwrap();
+        image::open(&fixture).unwrap().save(&sources[1]).unwrap();
+
+        let (input, out) = (dir.join("in"), dir.join("out"));
+        let config = parse([
+            "cutter",
+            "-p",
+            &input.to_string_lossy(),
+            "-t",
+            &out.to_string_lossy(),
+            "-s",
+            "20x20",
+        ]);
+        let report = transform_images(
+            sources,
+            &config.crop_sizes,
+            &config.transform_options(),
+            &config.encode_options(),
+            &config.output_options(),
+            Vec::new(),
+            &Progress::new(false, None, None),
+        )
+        .await;
+
+        let mut paths: Vec<&str> = report.crops.iter().map(|crop| crop.path.as_str()).collect();
+        paths.sort();
+        paths.dedup();
+        assert_eq!(paths.len(), 2, "{:?}", paths);
+        assert!(paths.iter().all(|path| Path::new(path).is_file()));
+    }
+
+    #[test]
+    fn corrupt_manifests_are_not_resumed() {
+        let path =
+            std::env::temp_dir().join(format!("cutter-test-manifest-{}.json", process::id()));
+        let path = path.to_string_lossy();
+        for contents in [
+            "[\n  {\"source\": \"a.jpg\", \"requested\": \"200x",
+            "[{\"source\": \"a.jpg\", \"requested\": \"200\", \"path\": \"a.jpg\", \"width\": 1, \"height\": 1}]",
+        ] {
+            fs::write(path.as_ref(), contents).unwrap();
+            assert!(resumable_crops(&path).is_empty());
+        }
+        fs::remove_file(path.as_ref()).unwrap();
+    }
+
+    #[test]
+    fn selftest_needs_no_path() {
+        let config = parse(["cutter", "selftest"]);
+        assert!(matches!(config.command, Some(Command::Selftest)));
+        assert_eq!(config.files_path, None);
+    }
+
+    #[test]
+    fn upload_only_needs_no_path() {
+        let config = parse(["cutter", "--upload-only", "-b", "bucket", "-t", "/tmp/out"]);
+        assert!(config.upload_only);
+        assert_eq!(config.files_path, None);
+    }
+
+    #[test]
+    fn crop_sizes_are_scaled() {
+        let mut config = parse([
+            "cutter",
+            "-p",
+            "/tmp/cutter-src",
+            "-s",
+            "200x100,1x1",
+            "--scale",
+            "1.5",
+        ]);
+        config.scale_crop_sizes().unwrap();
+        assert_eq!(config.crop_sizes, [Size(300, 150), Size(2, 2)]);
+
+        config.scale = Some(0.1);
+        config.scale_crop_sizes().unwrap();
+        assert_eq!(config.crop_sizes, [Size(30, 15), Size(1, 1)]);
+
+        config.scale = Some(10000.0);
+        assert!(config.scale_crop_sizes().is_err());
+    }
 }